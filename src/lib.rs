@@ -0,0 +1,4486 @@
+use chrono::{Local, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Read;
+use std::sync::Mutex;
+
+/// Why a query couldn't be parsed or run.
+#[derive(Debug)]
+pub enum Error {
+    /// The query text is malformed.
+    Parse(String),
+    /// The query doesn't fit the data, e.g. it names a missing column.
+    Query(String),
+    Io(std::io::Error),
+    Csv(csv::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(message) | Error::Query(message) => write!(f, "{}", message),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Csv(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(_) | Error::Query(_) => None,
+            Error::Io(e) => Some(e),
+            Error::Csv(e) => Some(e),
+            Error::Json(e) => Some(e),
+        }
+    }
+}
+
+/// Something a run wants to tell the user without failing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notice {
+    /// Data was skipped or changed, e.g. non-numeric cells in a sum.
+    Warning(String),
+    /// Detail asked for with `Query::verbose`, such as a sniffed delimiter.
+    Info(String),
+}
+
+/// The notices collected while a query runs. Nothing is printed by the
+/// library; callers drain them with `take` and show them as they like.
+#[derive(Debug, Default)]
+pub struct Notices(Mutex<Vec<Notice>>);
+
+impl Notices {
+    pub fn take(&self) -> Vec<Notice> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    fn warn(&self, message: String) {
+        self.lock().push(Notice::Warning(message));
+    }
+
+    fn info(&self, message: String) {
+        self.lock().push(Notice::Info(message));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Notice>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Errors from the query stages are plain messages about the data.
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Query(message)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        Error::Csv(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<glob::PatternError> for Error {
+    fn from(e: glob::PatternError) -> Self {
+        Error::Parse(e.to_string())
+    }
+}
+
+impl From<glob::GlobError> for Error {
+    fn from(e: glob::GlobError) -> Self {
+        Error::Io(e.into())
+    }
+}
+
+/// Header names plus data rows.
+pub type Table = (Vec<String>, Vec<Vec<String>>);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Query {
+    pub table_name: String,
+    pub joins: Vec<Join>,
+    // `split` and `merge` stages, applied in the order they were written.
+    pub reshapes: Vec<Reshape>,
+    // Let the last column of a `split` keep the unsplit rest of the value.
+    pub split_remainder: bool,
+    // Remove the source columns of a `merge`.
+    pub merge_drop: bool,
+    // All filters have to match for a row to be kept.
+    pub filters: Vec<Filter>,
+    // Stages using `or`, e.g. `a and b or c`. A row passes a stage when it
+    // matches every filter of at least one of its branches.
+    pub or_filters: Vec<Vec<Vec<Filter>>>,
+    pub columns: Option<Vec<String>>,
+    // `show region default "N/A"`: shown in place of an empty cell, or of
+    // every cell when the table has no such column.
+    pub column_defaults: HashMap<String, String>,
+    // `sort a, b desc`: columns with whether each one sorts descending. Later
+    // keys break ties of the earlier ones.
+    pub sort_keys: Vec<(String, bool)>,
+    // `--sort-precision N`: numbers are rounded to N decimals for sorting
+    // only, so `1.0000001` and `1.0` tie.
+    pub sort_precision: Option<u32>,
+    pub limit: Option<usize>,
+    pub split_by: Option<Vec<String>>,
+    pub latest_per: Option<LatestPer>,
+    pub cumsum: Option<CumulativeSum>,
+    pub group_column: Option<String>,
+    // Set by `group bucket(<column>, <width>)` to group numbers by range.
+    pub group_bucket_width: Option<f64>,
+    pub aggregate: Option<Aggregate>,
+    // The `minmax <column>` stage: earliest and latest date in the column.
+    pub minmax_column: Option<String>,
+    pub concat_separator: String,
+    // Order of grouped rows: `key`, `count`, `value-asc` or `value-desc`;
+    // `None` keeps the order groups first appear in.
+    pub group_sort: Option<String>,
+    // How the numeric aggregates treat empty cells: `skip`, `zero` or `error`.
+    pub agg_nulls: String,
+    pub strict: bool,
+    pub fail_if_empty: bool,
+    // The `columns` stage: list the header names instead of the data.
+    pub list_columns: bool,
+    // The `distinct` stage: drop repeated rows of the shown columns.
+    pub distinct: bool,
+    // The `with-count` stage: print a `Total: N` line after the results.
+    pub with_count: bool,
+    // Drop rows repeated across the files of a glob table.
+    pub union_distinct: bool,
+    // Extra column naming the file each row of a glob table came from
+    // (--with-source-column).
+    pub source_column: Option<String>,
+    // Replace invalid UTF-8 instead of failing the read (--lossy-utf8).
+    pub lossy_utf8: bool,
+    // How many rows make up the header (--header-rows), and what their parts
+    // are joined with (--header-join).
+    pub header_rows: usize,
+    pub header_join: String,
+    // `--shard K/N`: keep every N-th row of the table, starting at the K-th.
+    pub shard: Option<(usize, usize)>,
+    // Dotted path to the array of rows inside a JSON table, e.g. `data.items`.
+    pub json_path: Option<String>,
+    // Files left out of a glob table, matched against the path or file name.
+    #[serde(with = "pattern_strings")]
+    pub exclude: Vec<glob::Pattern>,
+    // Numbers use a decimal comma (--locale eu).
+    pub decimal_comma: bool,
+    // Field delimiter for reading tables; `None` sniffs it per file.
+    pub delimiter: Option<u8>,
+    pub verbose: bool,
+    // Warnings and verbose details from the last runs of this query.
+    #[serde(skip)]
+    pub notices: Notices,
+}
+
+// `join <table> on <keys> [= <keys>]`, an inner join. Keys are a column or a
+// parenthesized list of columns; without `=` both sides use the same names.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Join {
+    pub table: String,
+    pub table_name: String,
+    pub left_keys: Vec<String>,
+    pub right_keys: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Reshape {
+    Split(ColumnSplit),
+    Merge(ColumnMerge),
+    Hash(ColumnHash),
+}
+
+// `split <column> <delimiter> into <names>`, adding one column per name.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ColumnSplit {
+    pub column: String,
+    pub delimiter: String,
+    pub into: Vec<String>,
+}
+
+// `merge <columns> into <name> [with <separator>]`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ColumnMerge {
+    pub columns: Vec<String>,
+    pub into: String,
+    pub separator: String,
+}
+
+// `hash <columns> as <name>`, adding the SHA-256 hex digest of the columns.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ColumnHash {
+    pub columns: Vec<String>,
+    pub into: String,
+}
+
+// `latest-per <key_column> on <order_column>`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LatestPer {
+    pub key_column: String,
+    pub order_column: String,
+}
+
+// `cumsum <column> as <name>`, a running total of the column over the rows
+// in their final (sorted) order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CumulativeSum {
+    pub column: String,
+    pub into: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Aggregate {
+    pub function: String,
+    pub column: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Filter {
+    // More than one column, written `(a, b) contains x`, matches when any of
+    // them does.
+    pub columns: Vec<String>,
+    pub operator: String,
+    pub value: String,
+    // Lowercased members for `in` / `not in`, built once when parsing. For
+    // `is true` / `is false` these are the tokens that count as that value.
+    pub value_set: Option<HashSet<String>>,
+    // `num`, `date` or `text` when the column was written as e.g. `num(code)`,
+    // forcing how equals / greater / less compare. `magnitude` compares the
+    // cell's magnitude as a number.
+    pub cast: Option<String>,
+    // Inclusive bounds of `between X and Y` / `not between X and Y`.
+    pub range: Option<(String, String)>,
+    // The pattern of `matches`, compiled once when parsing. Plans leave it
+    // out; it is compiled again from `value` when a plan is loaded.
+    #[serde(skip)]
+    pub regex: Option<regex::Regex>,
+    // `id mod 2 equals 0` compares the cell's remainder; cast is then `mod`.
+    pub modulus: Option<i64>,
+    // `amount above p95`: the quantile, 0.95. Before running, `value` is
+    // replaced with the column's value at that quantile.
+    pub quantile: Option<f64>,
+    // Numbers use a decimal comma (--locale eu).
+    pub decimal_comma: bool,
+    // A relative date such as `today-7d` as written. `value` holds it resolved
+    // against the day of parsing; loading a plan resolves it again.
+    #[serde(default)]
+    pub relative: Option<String>,
+}
+
+// Reads `alias = operator` lines, e.g. `igual = equals` or `mayor = greater
+// than`. Aliases are single words; blank lines and `#` comments are skipped.
+pub fn load_operator_aliases(path: &str) -> Result<HashMap<String, String>, Error> {
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("Can't read operators file {}: {}", path, e),
+        )
+    })?;
+
+    let mut aliases = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (alias, operator) = line
+            .split_once('=')
+            .map(|(a, o)| (a.trim(), o.trim()))
+            .filter(|(a, o)| !a.is_empty() && !o.is_empty() && !a.contains(char::is_whitespace))
+            .ok_or_else(|| {
+                Error::Parse(format!(
+                    "{}:{}: expected 'alias = operator': {}",
+                    path,
+                    number + 1,
+                    line
+                ))
+            })?;
+        aliases.insert(alias.to_lowercase(), operator.to_string());
+    }
+    Ok(aliases)
+}
+
+// Keeps the rows of `results` whose `key` value occurs in `other`.
+pub fn intersect_results(
+    results: Vec<Vec<String>>,
+    other: &[Vec<String>],
+    key: &str,
+) -> Result<Vec<Vec<String>>, Error> {
+    let key_index = |table: &[Vec<String>], side: &str| {
+        table
+            .first()
+            .and_then(|header| header.iter().position(|h| h == key))
+            .ok_or_else(|| format!("Key column '{}' not in the {} result", key, side))
+    };
+    let left = key_index(&results, "first")?;
+    let right = key_index(other, "second")?;
+
+    let keys: HashSet<&str> = other[1..]
+        .iter()
+        .filter_map(|row| row.get(right))
+        .map(|k| k.as_str())
+        .collect();
+
+    let mut rows = results.into_iter();
+    let mut intersected: Vec<Vec<String>> = rows.next().into_iter().collect();
+    intersected.extend(rows.filter(|row| row.get(left).is_some_and(|k| keys.contains(k.as_str()))));
+    Ok(intersected)
+}
+
+// Scores every row against the search words and keeps the `top` best, best
+// first, with the score in a leading column. Each search word counts with its
+// closest word anywhere in the row (Jaro-Winkler similarity), so typos and
+// partial names still rank; the score is the average over the search words.
+pub fn search_table(
+    header_names: Vec<String>,
+    rows: Vec<Vec<String>>,
+    text: &str,
+    top: usize,
+) -> Vec<Vec<String>> {
+    let terms: Vec<String> = text.split_whitespace().map(|t| t.to_lowercase()).collect();
+
+    let mut scored: Vec<(f64, Vec<String>)> = rows
+        .into_iter()
+        .map(|row| {
+            let words: Vec<String> = row
+                .iter()
+                .flat_map(|cell| cell.split(|c: char| !c.is_alphanumeric()))
+                .filter(|w| !w.is_empty())
+                .map(|w| w.to_lowercase())
+                .collect();
+            let total: f64 = terms
+                .iter()
+                .map(|term| {
+                    words
+                        .iter()
+                        .map(|word| strsim::jaro_winkler(term, word))
+                        .fold(0.0, f64::max)
+                })
+                .sum();
+            (total / terms.len().max(1) as f64, row)
+        })
+        .filter(|(score, _)| *score > 0.0)
+        .collect();
+    // Stable, so equally good rows stay in file order.
+    scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+
+    let mut header = vec!["score".to_string()];
+    header.extend(header_names);
+    let mut result = vec![header];
+    for (score, row) in scored.into_iter().take(top) {
+        let mut line = vec![format!("{:.3}", score)];
+        line.extend(row);
+        result.push(line);
+    }
+    result
+}
+
+// One row per column: its name, inferred type and up to three distinct
+// non-empty example values.
+pub fn glance_table(header_names: &[String], rows: &[Vec<String>]) -> Vec<Vec<String>> {
+    let mut result = vec![vec![
+        "column".to_string(),
+        "type".to_string(),
+        "examples".to_string(),
+    ]];
+
+    for (i, name) in header_names.iter().enumerate() {
+        let values: Vec<&str> = rows
+            .iter()
+            .filter_map(|row| row.get(i))
+            .map(|v| v.as_str())
+            .filter(|v| !v.trim().is_empty())
+            .collect();
+
+        let mut examples: Vec<&str> = Vec::new();
+        for value in &values {
+            if examples.len() == 3 {
+                break;
+            }
+            if !examples.contains(value) {
+                examples.push(value);
+            }
+        }
+
+        result.push(vec![
+            name.clone(),
+            infer_column_type(&values).to_string(),
+            examples.join(", "),
+        ]);
+    }
+
+    result
+}
+
+/// The narrowest type every value fits: integer, float, bool, date or text,
+/// or "empty" without values.
+pub fn infer_column_type(values: &[&str]) -> &'static str {
+    if values.is_empty() {
+        return "empty";
+    }
+    let inferred: Vec<InferredValue> = values.iter().map(|v| infer_value(v)).collect();
+    if inferred
+        .iter()
+        .all(|v| matches!(v, InferredValue::Integer(_)))
+    {
+        "integer"
+    } else if inferred
+        .iter()
+        .all(|v| matches!(v, InferredValue::Integer(_) | InferredValue::Float(_)))
+    {
+        "float"
+    } else if inferred.iter().all(|v| matches!(v, InferredValue::Bool(_))) {
+        "bool"
+    } else if values.iter().all(|v| parse_date(v).is_some()) {
+        "date"
+    } else {
+        "text"
+    }
+}
+
+// Words that start a non-filter stage. Under `--strict-parse` a filter whose
+// first word is a near miss of one of these is rejected as a likely typo.
+const STAGE_KEYWORDS: &[&str] = &[
+    "show",
+    "sort",
+    "take",
+    "limit",
+    "split",
+    "split-by",
+    "merge",
+    "hash",
+    "group",
+    "all",
+    "any",
+    "count",
+    "sum",
+    "avg",
+    "min",
+    "max",
+    "first-nonempty",
+    "last-nonempty",
+    "where",
+    "since",
+    "until",
+    "latest-per",
+    "cumsum",
+    "with-count",
+    "distinct",
+    "join",
+    "columns",
+    "minmax",
+];
+
+/// Table name that reads the table from stdin.
+pub const STDIN_TABLE: &str = "-";
+
+// `glob::Pattern` has no serde support; plans store the pattern text.
+mod pattern_strings {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(patterns: &[glob::Pattern], s: S) -> Result<S::Ok, S::Error> {
+        let texts: Vec<&str> = patterns.iter().map(|p| p.as_str()).collect();
+        texts.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<glob::Pattern>, D::Error> {
+        Vec::<String>::deserialize(d)?
+            .iter()
+            .map(|text| glob::Pattern::new(text).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+/// Writes the parsed query, settings included, as JSON for `load_plan`.
+pub fn save_plan(query: &Query) -> Result<String, Error> {
+    Ok(serde_json::to_string_pretty(query)?)
+}
+
+/// Reads a query saved by `save_plan`. Relative dates like `today-7d` count
+/// from the day the plan is loaded, not the day it was saved.
+pub fn load_plan(json: &str) -> Result<Query, Error> {
+    load_plan_at(json, Local::now().date_naive())
+}
+
+fn load_plan_at(json: &str, today: NaiveDate) -> Result<Query, Error> {
+    let mut query: Query = serde_json::from_str(json)?;
+    let filters = query
+        .filters
+        .iter_mut()
+        .chain(query.or_filters.iter_mut().flatten().flatten());
+    for filter in filters {
+        if let Some(date) = filter
+            .relative
+            .as_deref()
+            .and_then(|token| resolve_relative_date(token, today))
+        {
+            filter.value = date.format("%Y-%m-%d").to_string();
+        }
+        if filter.operator == "matches" {
+            filter.regex = Some(compile_pattern(&filter.value).map_err(|e| {
+                Error::Parse(format!("Invalid pattern '{}' in plan: {}", filter.value, e))
+            })?);
+        }
+    }
+    Ok(query)
+}
+
+/// Parses a query such as `employees >> salary greater than 50000 >> show name`.
+/// `default_table` is used when the query starts with `>>`; without one the
+/// table is read from stdin, as with a `-` table name.
+pub fn parse_query(
+    input: &str,
+    default_table: Option<&str>,
+    strict_parse: bool,
+    operator_aliases: &HashMap<String, String>,
+) -> Result<Query, Error> {
+    parse_stages(input, default_table, strict_parse, operator_aliases).map_err(Error::Parse)
+}
+
+fn parse_stages(
+    input: &str,
+    default_table: Option<&str>,
+    strict_parse: bool,
+    operator_aliases: &HashMap<String, String>,
+) -> Result<Query, String> {
+    let input = strip_trailing_comment(input);
+    let parts: Vec<&str> = input.split(">>").map(|s| s.trim()).collect();
+
+    if parts.is_empty() {
+        return Err("Empty query".to_string());
+    }
+
+    // First part is always the table name, unless the query starts with `>>`
+    let table_name = match (parts[0], default_table) {
+        ("", Some(table)) => table.to_string(),
+        ("", None) => STDIN_TABLE.to_string(),
+        (table, _) => table.to_string(),
+    };
+
+    let mut query = Query {
+        table_name: table_file_name(&table_name),
+        joins: Vec::new(),
+        reshapes: Vec::new(),
+        split_remainder: false,
+        merge_drop: false,
+        filters: Vec::new(),
+        or_filters: Vec::new(),
+        columns: None,
+        column_defaults: HashMap::new(),
+        sort_keys: Vec::new(),
+        sort_precision: None,
+        limit: None,
+        split_by: None,
+        latest_per: None,
+        cumsum: None,
+        group_column: None,
+        group_bucket_width: None,
+        aggregate: None,
+        minmax_column: None,
+        concat_separator: ", ".to_string(),
+        group_sort: None,
+        agg_nulls: "skip".to_string(),
+        strict: false,
+        fail_if_empty: false,
+        list_columns: false,
+        with_count: false,
+        distinct: false,
+        union_distinct: false,
+        source_column: None,
+        shard: None,
+        lossy_utf8: false,
+        header_rows: 1,
+        header_join: " / ".to_string(),
+        json_path: None,
+        exclude: Vec::new(),
+        decimal_comma: false,
+        delimiter: Some(b','),
+        verbose: false,
+        notices: Notices::default(),
+    };
+
+    for part in &parts[1..] {
+        let words: Vec<&str> = part.split_whitespace().collect();
+
+        if words.is_empty() {
+            continue;
+        }
+
+        match words[0].to_lowercase().as_str() {
+            "show" => {
+                let columns_str = part["show".len()..].trim();
+                // Quoted names may contain commas: show "last, first", age
+                let mut columns = Vec::new();
+                for item in split_list(columns_str) {
+                    let (column, default) = split_column_default(item);
+                    let column = unquote(column).trim().to_string();
+                    if let Some(default) = default {
+                        if default.is_empty() {
+                            return Err(format!("Missing value after 'default' in: {}", part));
+                        }
+                        query
+                            .column_defaults
+                            .insert(column.clone(), unquote(default).to_string());
+                    }
+                    columns.push(column);
+                }
+                query.columns = Some(columns);
+            }
+            "sort" => {
+                query.sort_keys = parse_sort_keys(part["sort".len()..].trim())?;
+            }
+            "take" | "limit" => {
+                if words.len() >= 2 {
+                    query.limit = words[1].parse().ok();
+                }
+            }
+            "split-by" => {
+                let columns: Vec<String> = part["split-by".len()..]
+                    .split(',')
+                    .map(|c| c.trim().to_string())
+                    .filter(|c| !c.is_empty())
+                    .collect();
+                if columns.is_empty() {
+                    return Err(format!("Missing column in split-by: {}", part));
+                }
+                query.split_by = Some(columns);
+            }
+            "join" => {
+                query.joins.push(parse_join(part)?);
+            }
+            "split" => {
+                query
+                    .reshapes
+                    .push(Reshape::Split(parse_column_split(part)?));
+            }
+            "merge" => {
+                query
+                    .reshapes
+                    .push(Reshape::Merge(parse_column_merge(part)?));
+            }
+            "hash" => {
+                query.reshapes.push(Reshape::Hash(parse_column_hash(part)?));
+            }
+            "columns" => {
+                query.list_columns = true;
+            }
+            "with-count" => {
+                query.with_count = true;
+            }
+            "distinct" => {
+                query.distinct = true;
+            }
+            "latest-per" => {
+                if words.len() != 4 || !words[2].eq_ignore_ascii_case("on") {
+                    return Err(format!(
+                        "Expected 'latest-per <column> on <column>': {}",
+                        part
+                    ));
+                }
+                query.latest_per = Some(LatestPer {
+                    key_column: words[1].to_string(),
+                    order_column: words[3].to_string(),
+                });
+            }
+            "cumsum" => {
+                if words.len() != 4 || !words[2].eq_ignore_ascii_case("as") {
+                    return Err(format!("Expected 'cumsum <column> as <name>': {}", part));
+                }
+                query.cumsum = Some(CumulativeSum {
+                    column: words[1].to_string(),
+                    into: words[3].to_string(),
+                });
+            }
+            "since" | "until" => {
+                query
+                    .filters
+                    .push(parse_date_bound(&words, Local::now().date_naive())?);
+            }
+            // Longer stages starting with these words are filters on a column
+            // of that name, e.g. `count greater than 3`.
+            "count" | "sum" | "avg" | "min" | "max" | "first-nonempty" | "last-nonempty"
+            | "all" | "any"
+                if words.len() <= 2 =>
+            {
+                query.aggregate = Some(parse_aggregate(&words)?);
+            }
+            "minmax" => {
+                if words.len() != 2 {
+                    return Err(format!("Expected 'minmax <column>': {}", part));
+                }
+                query.minmax_column = Some(words[1].to_string());
+            }
+            "group" => {
+                let rest = part["group".len()..].trim();
+                let aggregate_words: Vec<&str> = if let Some(args) = rest.strip_prefix("bucket(") {
+                    let (args, remainder) = args
+                        .split_once(')')
+                        .ok_or_else(|| format!("Unclosed bucket( in group: {}", part))?;
+                    let (column, width) = args
+                        .split_once(',')
+                        .ok_or_else(|| format!("Expected bucket(<column>, <width>): {}", part))?;
+                    let width: f64 = width
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|w: &f64| *w > 0.0)
+                        .ok_or_else(|| format!("Invalid bucket width in group: {}", part))?;
+                    query.group_column = Some(column.trim().to_string());
+                    query.group_bucket_width = Some(width);
+                    remainder.split_whitespace().collect()
+                } else {
+                    if words.len() < 2 {
+                        return Err(format!("Missing column in group: {}", part));
+                    }
+                    query.group_column = Some(words[1].to_string());
+                    words[2..].to_vec()
+                };
+                if !aggregate_words.is_empty() {
+                    query.aggregate = Some(parse_aggregate(&aggregate_words)?);
+                }
+            }
+            _ => {
+                // `country equals US` is a filter on `country`, not a typo of
+                // `count`: only a stage that doesn't read as a filter is checked.
+                let reads_as_filter = words.get(1).is_some_and(|word| {
+                    is_operator_word(word) || operator_aliases.contains_key(&word.to_lowercase())
+                });
+                if strict_parse
+                    && !reads_as_filter
+                    && let Some(keyword) = misspelled_keyword(words[0])
+                {
+                    return Err(format!(
+                        "Unknown stage '{}'; did you mean '{}'?",
+                        words[0], keyword
+                    ));
+                }
+                let mut branches: Vec<Vec<Filter>> = vec![Vec::new()];
+                for (connector, clause) in split_clauses(part, operator_aliases) {
+                    if connector == Some("or") {
+                        branches.push(Vec::new());
+                    }
+                    if let Some(filter) = parse_filter(&clause)? {
+                        branches.last_mut().unwrap().push(filter);
+                    }
+                }
+                if branches.len() == 1 {
+                    query.filters.append(&mut branches[0]);
+                } else {
+                    query.or_filters.push(branches);
+                }
+            }
+        }
+    }
+
+    Ok(query)
+}
+
+// Table names get a `.csv` extension unless they already have one, like
+// `data.tsv`. A name such as `sales.2024` still finds `sales.2024.csv`.
+fn table_file_name(table: &str) -> String {
+    if table == STDIN_TABLE {
+        return table.to_string();
+    }
+    if let Some((archive, entry)) = split_archive_entry(table) {
+        return match std::path::Path::new(entry).extension() {
+            Some(_) => table.to_string(),
+            None => format!("{}!{}.csv", archive, entry),
+        };
+    }
+    let with_csv = format!("{}.csv", table);
+    let path = std::path::Path::new(table);
+    if path.extension().is_some() && (path.exists() || !std::path::Path::new(&with_csv).exists()) {
+        table.to_string()
+    } else {
+        with_csv
+    }
+}
+
+fn parse_join(part: &str) -> Result<Join, String> {
+    let words: Vec<&str> = part.split_whitespace().collect();
+    if words.len() < 4 || !words[2].eq_ignore_ascii_case("on") {
+        return Err(format!("Expected 'join <table> on <keys>': {}", part));
+    }
+
+    let keys = words[3..].join(" ");
+    let (left, right) = match keys.split_once('=') {
+        Some((left, right)) => (parse_key_list(left), parse_key_list(right)),
+        None => (parse_key_list(&keys), parse_key_list(&keys)),
+    };
+
+    if left.is_empty() || right.is_empty() {
+        return Err(format!("Missing join keys: {}", part));
+    }
+    if left.len() != right.len() {
+        return Err(format!(
+            "Join keys don't line up: {} on the left, {} on the right: {}",
+            left.len(),
+            right.len(),
+            part
+        ));
+    }
+
+    Ok(Join {
+        table: words[1].to_string(),
+        table_name: table_file_name(words[1]),
+        left_keys: left,
+        right_keys: right,
+    })
+}
+
+// `split full_name " " into first, last`. The delimiter is a single word or
+// a quoted string, so spaces and commas can be split on too.
+fn parse_column_split(part: &str) -> Result<ColumnSplit, String> {
+    let usage = || {
+        format!(
+            "Expected 'split <column> <delimiter> into <names>': {}",
+            part
+        )
+    };
+    let rest = part["split".len()..].trim_start();
+    let (column, rest) = rest.split_once(char::is_whitespace).ok_or_else(usage)?;
+    let rest = rest.trim_start();
+
+    let (delimiter, rest) = match rest.chars().next() {
+        Some(quote @ ('"' | '\'')) => {
+            let end = rest[1..].find(quote).ok_or_else(usage)? + 1;
+            (&rest[1..end], &rest[end + 1..])
+        }
+        _ => rest.split_once(char::is_whitespace).ok_or_else(usage)?,
+    };
+
+    let rest = rest.trim_start();
+    let names = match rest.get(..5) {
+        Some(keyword) if keyword.eq_ignore_ascii_case("into ") => &rest[5..],
+        _ => return Err(usage()),
+    };
+    let into: Vec<String> = split_list(names)
+        .into_iter()
+        .map(|name| unquote(name).trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    if delimiter.is_empty() || into.is_empty() {
+        return Err(usage());
+    }
+
+    Ok(ColumnSplit {
+        column: column.to_string(),
+        delimiter: delimiter.to_string(),
+        into,
+    })
+}
+
+// `merge first, last into name with " "`. Without `with` the values are
+// joined by a single space.
+fn parse_column_merge(part: &str) -> Result<ColumnMerge, String> {
+    let usage = || {
+        format!(
+            "Expected 'merge <columns> into <name> [with <separator>]': {}",
+            part
+        )
+    };
+    let rest = &part["merge".len()..];
+    let into_at = keyword_offsets(rest, " into ").next().ok_or_else(usage)?;
+    let (columns, target) = (&rest[..into_at], rest[into_at + " into ".len()..].trim());
+
+    let (into, separator) = match keyword_offsets(target, " with ").next() {
+        Some(with_at) => (
+            target[..with_at].trim(),
+            unquote(target[with_at + " with ".len()..].trim()),
+        ),
+        None => (target, " "),
+    };
+    let columns: Vec<String> = split_list(columns)
+        .into_iter()
+        .map(|column| unquote(column).trim().to_string())
+        .filter(|column| !column.is_empty())
+        .collect();
+    let into = unquote(into).trim();
+
+    if columns.is_empty() || into.is_empty() {
+        return Err(usage());
+    }
+
+    Ok(ColumnMerge {
+        columns,
+        into: into.to_string(),
+        separator: separator.to_string(),
+    })
+}
+
+// Byte offsets of `keyword` in `text`, ignoring ASCII case. Searching a
+// lowercased copy instead gives offsets that can be off in `text`, as
+// lowercasing changes the length of some characters.
+fn keyword_offsets<'a>(text: &'a str, keyword: &'a str) -> impl Iterator<Item = usize> + 'a {
+    let (text, keyword) = (text.as_bytes(), keyword.as_bytes());
+    (0..(text.len() + 1).saturating_sub(keyword.len()))
+        .filter(move |&i| text[i..i + keyword.len()].eq_ignore_ascii_case(keyword))
+}
+
+// `hash <columns> as <name>`
+fn parse_column_hash(part: &str) -> Result<ColumnHash, String> {
+    let usage = || format!("Expected 'hash <columns> as <name>': {}", part);
+    let rest = &part["hash".len()..];
+    let as_at = keyword_offsets(rest, " as ").last().ok_or_else(usage)?;
+    let columns: Vec<String> = split_list(&rest[..as_at])
+        .into_iter()
+        .map(|column| unquote(column).trim().to_string())
+        .filter(|column| !column.is_empty())
+        .collect();
+    let into = unquote(rest[as_at + " as ".len()..].trim()).trim();
+
+    if columns.is_empty() || into.is_empty() {
+        return Err(usage());
+    }
+
+    Ok(ColumnHash {
+        columns,
+        into: into.to_string(),
+    })
+}
+
+// `name asc, salary desc, hired`; keys without a direction sort ascending.
+fn parse_sort_keys(keys: &str) -> Result<Vec<(String, bool)>, String> {
+    let mut sort_keys = Vec::new();
+    for key in split_list(keys) {
+        let key = key.trim();
+        if key.is_empty() {
+            continue;
+        }
+        let (column, direction) = match key.rsplit_once(char::is_whitespace) {
+            Some((column, direction))
+                if direction.eq_ignore_ascii_case("asc")
+                    || direction.eq_ignore_ascii_case("desc") =>
+            {
+                (column.trim(), Some(direction))
+            }
+            _ => (key, None),
+        };
+        let column = unquote(column).trim();
+        if column.is_empty() || (!key.starts_with('"') && column.contains(char::is_whitespace)) {
+            return Err(format!(
+                "Expected 'sort <column> [asc|desc], ...': sort {}",
+                keys
+            ));
+        }
+        let descending = direction.is_some_and(|d| d.eq_ignore_ascii_case("desc"));
+        sort_keys.push((column.to_string(), descending));
+    }
+    Ok(sort_keys)
+}
+
+// `id` or `(order_id, line_no)`
+fn parse_key_list(keys: &str) -> Vec<String> {
+    let keys = keys.trim();
+    let keys = keys
+        .strip_prefix('(')
+        .and_then(|k| k.strip_suffix(')'))
+        .unwrap_or(keys);
+    keys.split(',')
+        .map(|k| k.trim().to_string())
+        .filter(|k| !k.is_empty())
+        .collect()
+}
+
+// Returns the stage keyword `word` is most likely a typo of. Short keywords
+// only allow a single edit so that columns like `age` aren't mistaken for `any`.
+fn misspelled_keyword(word: &str) -> Option<&'static str> {
+    let word = word.to_lowercase();
+    STAGE_KEYWORDS
+        .iter()
+        .map(|keyword| (strsim::damerau_levenshtein(&word, keyword), *keyword))
+        .filter(|(distance, keyword)| {
+            let max_distance = if keyword.len() <= 4 { 1 } else { 2 };
+            *distance > 0 && *distance <= max_distance
+        })
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, keyword)| keyword)
+}
+
+// Parses `<function> [column]`, e.g. `group-concat product`.
+fn parse_aggregate(words: &[&str]) -> Result<Aggregate, String> {
+    let function = words[0].to_lowercase();
+    let column = words.get(1).map(|c| c.to_string());
+
+    match function.as_str() {
+        "group-concat" | "all" | "any" | "sum" | "avg" | "min" | "max" | "first-nonempty"
+        | "last-nonempty" => {
+            if column.is_none() {
+                return Err(format!("Missing column for {}", function));
+            }
+        }
+        "count" => {}
+        _ => return Err(format!("Unknown aggregate: {}", words[0])),
+    }
+
+    Ok(Aggregate { function, column })
+}
+
+// Drops a shell-style `# ...` comment from the end of the query. A `#` only
+// starts a comment at the beginning of a word and outside of quotes, so values
+// like `A#1` or `"#1"` are left alone.
+fn strip_trailing_comment(input: &str) -> &str {
+    let mut quote: Option<char> = None;
+    let mut prev = ' ';
+
+    for (i, c) in input.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if opens_quote(input, i, c) => quote = Some(c),
+            None if c == '#' && prev.is_whitespace() => return &input[..i],
+            None => {}
+        }
+        prev = c;
+    }
+
+    input
+}
+
+// A quote only opens at the start of a word or list item, so the apostrophe
+// in `O'Brien` is part of the word.
+fn opens_quote(text: &str, i: usize, c: char) -> bool {
+    (c == '"' || c == '\'')
+        && text[..i]
+            .chars()
+            .next_back()
+            .is_none_or(|prev| prev.is_whitespace() || prev == '(' || prev == ',')
+}
+
+// Splits a filter stage on the `and` / `or` between its clauses, pairing each
+// clause with the connector before it. A connector only counts when a whole
+// clause (column, operator, value) follows it, so values keep their own words:
+// `title contains rock and roll` is a single clause. Quoted and parenthesized
+// text is never split, and neither is the `and` of `between X and Y`.
+// Operators written as one of the `aliases` are replaced by the operator they
+// stand for.
+fn split_clauses(
+    stage: &str,
+    aliases: &HashMap<String, String>,
+) -> Vec<(Option<&'static str>, String)> {
+    let mut stage = stage.trim();
+    if let Some((first, remainder)) = stage.split_once(char::is_whitespace)
+        && first.eq_ignore_ascii_case("where")
+    {
+        stage = remainder.trim_start();
+    }
+
+    // Word spans, keeping quoted and parenthesized text together.
+    let mut words: Vec<(usize, usize)> = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut depth = 0;
+    let mut start: Option<usize> = None;
+    for (i, c) in stage.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if opens_quote(stage, i, c) => quote = Some(c),
+            None if c == '(' => depth += 1,
+            None if c == ')' => depth -= 1,
+            None if c.is_whitespace() && depth <= 0 => {
+                if let Some(s) = start.take() {
+                    words.push((s, i));
+                }
+                continue;
+            }
+            None => {}
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(s) = start {
+        words.push((s, stage.len()));
+    }
+    let words: Vec<&str> = words.into_iter().map(|(s, e)| &stage[s..e]).collect();
+    if words.is_empty() {
+        return vec![(None, stage.to_string())];
+    }
+    let operator = |word: &str| {
+        let word = word.to_lowercase();
+        aliases.get(&word).cloned().unwrap_or(word)
+    };
+
+    let mut clauses = Vec::new();
+    let mut connector = None;
+    let mut clause_start = 0;
+    let mut open_between = false;
+    for i in 0..words.len() {
+        let keyword = match operator(words[i]).as_str() {
+            "between" => {
+                open_between = true;
+                continue;
+            }
+            "and" if open_between => {
+                open_between = false;
+                continue;
+            }
+            "and" => "and",
+            "or" => "or",
+            _ => continue,
+        };
+        let follows_clause = i >= clause_start + 3;
+        let starts_clause = i + 3 < words.len()
+            && operator(words[i + 2])
+                .split_whitespace()
+                .next()
+                .is_some_and(is_operator_word);
+        if follows_clause && starts_clause {
+            clauses.push((connector, clause_text(&words[clause_start..i], aliases)));
+            connector = Some(keyword);
+            clause_start = i + 1;
+        }
+    }
+    clauses.push((connector, clause_text(&words[clause_start..], aliases)));
+    clauses
+}
+
+// Rejoins a clause's words, replacing an aliased operator.
+fn clause_text(words: &[&str], aliases: &HashMap<String, String>) -> String {
+    let mut words: Vec<&str> = words.to_vec();
+    let at = match words.get(1) {
+        Some(word) if word.eq_ignore_ascii_case("magnitude") => 2,
+        Some(word) if word.eq_ignore_ascii_case("mod") => 3,
+        _ => 1,
+    };
+    if let Some(word) = words.get_mut(at)
+        && let Some(operator) = aliases.get(&word.to_lowercase())
+    {
+        *word = operator;
+    }
+    words.join(" ")
+}
+
+// The word after the column in a filter clause.
+fn is_operator_word(word: &str) -> bool {
+    matches!(
+        word,
+        "greater"
+            | "less"
+            | "equals"
+            | "contains"
+            | "matches"
+            | "above"
+            | "below"
+            | "starts"
+            | "ends"
+            | "in"
+            | "not"
+            | "between"
+            | "is"
+            | "magnitude"
+            | "mod"
+    ) || word.chars().all(|c| "<>=!~".contains(c))
+}
+
+fn parse_filter(filter_str: &str) -> Result<Option<Filter>, String> {
+    parse_filter_at(filter_str, Local::now().date_naive())
+}
+
+// Same as `parse_filter`, but relative date values like `today-7d` are
+// resolved against the given `today` instead of the system clock.
+fn parse_filter_at(filter_str: &str, today: NaiveDate) -> Result<Option<Filter>, String> {
+    let mut rest = filter_str.trim();
+    if let Some((first, remainder)) = rest.split_once(char::is_whitespace)
+        && first.eq_ignore_ascii_case("where")
+    {
+        rest = remainder.trim_start();
+    }
+
+    // A parenthesized column list stands in for the usual single column word,
+    // so the operator and value still start at `words[1]`.
+    let (columns, words) = if let Some(list) = rest.strip_prefix('(') {
+        // Filters don't nest, so `((a))` is an error rather than a column
+        // named `(a`.
+        if list.trim_start().starts_with('(') {
+            return Err(format!(
+                "Nested parentheses aren't supported in a filter: {}",
+                filter_str
+            ));
+        }
+        let (list, remainder) = list
+            .split_once(')')
+            .ok_or_else(|| format!("Unclosed column list in filter: {}", filter_str))?;
+        let columns: Vec<String> = list
+            .split(',')
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty())
+            .collect();
+        let mut words = vec!["(...)"];
+        words.extend(remainder.split_whitespace());
+        (columns, words)
+    } else {
+        let words: Vec<&str> = rest.split_whitespace().collect();
+        let columns = words
+            .first()
+            .map(|c| vec![c.to_string()])
+            .unwrap_or_default();
+        (columns, words)
+    };
+
+    if words.len() < 3 || columns.is_empty() {
+        return Err(format!("Invalid filter: {}", filter_str));
+    }
+
+    let (columns, cast) = match columns.as_slice() {
+        [column] => match split_cast(column) {
+            Some((cast, inner)) => (vec![inner.to_string()], Some(cast.to_string())),
+            None => (columns, None),
+        },
+        _ => (columns, None),
+    };
+
+    // `amount magnitude equals 3` compares the number's magnitude instead of
+    // the number itself.
+    let mut words = words;
+    let cast = if words.len() >= 4 && words[1].eq_ignore_ascii_case("magnitude") {
+        words.remove(1);
+        Some("magnitude".to_string())
+    } else {
+        cast
+    };
+
+    // `id mod 2 equals 0` compares the remainder of dividing by 2.
+    let mut modulus = None;
+    let cast = if words.len() >= 5 && words[1].eq_ignore_ascii_case("mod") {
+        let divisor = words[2]
+            .parse::<i64>()
+            .ok()
+            .filter(|&d| d != 0)
+            .ok_or_else(|| format!("Expected a non-zero whole number after mod: {}", filter_str))?;
+        modulus = Some(divisor);
+        words.drain(1..3);
+        Some("mod".to_string())
+    } else {
+        cast
+    };
+
+    let (operator, value_start_index) = {
+        if words.len() >= 4 && words[1] == "greater" && words[2] == "than" {
+            ("greater".to_string(), 3)
+        } else if words.len() >= 4 && words[1] == "less" && words[2] == "than" {
+            ("less".to_string(), 3)
+        } else if words[1] == "equals" {
+            ("equals".to_string(), 2)
+        } else if words.len() >= 4 && words[1] == "not" && words[2] == "equals" {
+            ("not equals".to_string(), 3)
+        } else if words[1] == "contains" {
+            ("contains".to_string(), 2)
+        } else if words[1] == "matches" {
+            ("matches".to_string(), 2)
+        } else if words[1] == "above" || words[1] == "below" {
+            (words[1].to_string(), 2)
+        } else if words[1] == "starts" || words[1] == "ends" {
+            // `starts with` / `ends with`, or just `starts` / `ends`
+            let skip_with = words.len() >= 4 && words[2] == "with";
+            (words[1].to_string(), if skip_with { 3 } else { 2 })
+        } else if words[1] == "in" {
+            ("in".to_string(), 2)
+        } else if words.len() >= 4 && words[1] == "not" && words[2] == "in" {
+            ("not in".to_string(), 3)
+        } else if words[1] == "between" {
+            ("between".to_string(), 2)
+        } else if words.len() >= 4 && words[1] == "not" && words[2] == "between" {
+            ("not between".to_string(), 3)
+        } else if words[1] == "is" {
+            ("is".to_string(), 2)
+        } else {
+            (words[1].to_string(), 2)
+        }
+    };
+
+    if words.len() <= value_start_index {
+        return Err(format!("Missing value in filter: {}", filter_str));
+    }
+    let mut value = words[value_start_index..].join(" ");
+    // Quotes keep `and` / `or` in a value from splitting the stage; lists and
+    // patterns take their quotes off themselves.
+    if !matches!(operator.as_str(), "in" | "not in" | "matches") {
+        value = unquote(&value).to_string();
+    }
+    let mut relative = None;
+    if let Some(date) = resolve_relative_date(&value, today) {
+        relative = Some(std::mem::replace(
+            &mut value,
+            date.format("%Y-%m-%d").to_string(),
+        ));
+    }
+
+    let value_set = if operator == "in" || operator == "not in" {
+        Some(token_set(&value))
+    } else if operator == "is" {
+        value = value.to_lowercase();
+        match value.as_str() {
+            "true" => Some(token_set(DEFAULT_TRUE_VALUES)),
+            "false" => Some(token_set(DEFAULT_FALSE_VALUES)),
+            "integer" | "float" | "numeric" | "not numeric" => None,
+            _ => {
+                return Err(format!(
+                    "Expected 'is true', 'is false', 'is integer', 'is float' or 'is [not] numeric': {}",
+                    filter_str
+                ));
+            }
+        }
+    } else {
+        None
+    };
+
+    let regex = if operator == "matches" {
+        Some(
+            compile_pattern(&value)
+                .map_err(|e| format!("Invalid pattern in filter: {}\n{}", filter_str, e))?,
+        )
+    } else {
+        None
+    };
+
+    let quantile = if operator == "above" || operator == "below" {
+        let percent = value
+            .strip_prefix(['p', 'P'])
+            .and_then(|p| p.parse::<f64>().ok())
+            .filter(|p| (0.0..=100.0).contains(p))
+            .ok_or_else(|| {
+                format!(
+                    "Expected '{} pNN' with a percentile from p0 to p100: {}",
+                    operator, filter_str
+                )
+            })?;
+        Some(percent / 100.0)
+    } else {
+        None
+    };
+
+    let range = if operator == "between" || operator == "not between" {
+        Some(parse_range(&value).ok_or_else(|| {
+            format!(
+                "Expected '{} <low> and <high>' with numbers or dates: {}",
+                operator, filter_str
+            )
+        })?)
+    } else {
+        None
+    };
+
+    Ok(Some(Filter {
+        columns,
+        operator,
+        value,
+        value_set,
+        cast,
+        range,
+        regex,
+        modulus,
+        quantile,
+        decimal_comma: false,
+        relative,
+    }))
+}
+
+// The value of a `matches` filter. Case-sensitive unless it ends in `/i`.
+fn compile_pattern(value: &str) -> Result<regex::Regex, regex::Error> {
+    let pattern = unquote(value);
+    let (pattern, ignore_case) = match pattern.strip_suffix("/i") {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    regex::RegexBuilder::new(pattern)
+        .case_insensitive(ignore_case)
+        .build()
+}
+
+// `10 and 20` or `2024-01-01 and 2024-06-30`; both bounds must be the same kind.
+fn parse_range(value: &str) -> Option<(String, String)> {
+    let and_at = keyword_offsets(value, " and ").next()?;
+    let low = value[..and_at].trim();
+    let high = value[and_at + " and ".len()..].trim();
+    // Lenient about the decimal separator, as --locale isn't known yet.
+    let numbers = parse_number(low, true).is_some() && parse_number(high, true).is_some();
+    let dates = parse_date(low).is_some() && parse_date(high).is_some();
+    (numbers || dates).then(|| (low.to_string(), high.to_string()))
+}
+
+// Tokens `is true` / `is false` accept unless --true-values / --false-values
+// say otherwise.
+pub const DEFAULT_TRUE_VALUES: &str = "true,t,yes,y,1";
+
+pub const DEFAULT_FALSE_VALUES: &str = "false,f,no,n,0";
+
+// Lowercased members of a comma separated list such as `yes, y, 1`.
+pub fn token_set(list: &str) -> HashSet<String> {
+    parse_value_list(list)
+        .into_iter()
+        .map(|v| v.to_lowercase())
+        .collect()
+}
+
+// Splits `num(code)` into `("num", "code")`.
+fn split_cast(column: &str) -> Option<(&str, &str)> {
+    let (cast, rest) = column.split_once('(')?;
+    let inner = rest.strip_suffix(')')?;
+    match cast.to_lowercase().as_str() {
+        "num" | "date" | "text" if !inner.is_empty() => Some((cast, inner)),
+        _ => None,
+    }
+}
+
+// Splits `a, b, c` or `(a, b, c)` into its items, dropping quotes around
+// individual values so `("New York", Berlin)` works.
+fn parse_value_list(value: &str) -> Vec<String> {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('(')
+        .and_then(|v| v.strip_suffix(')'))
+        .unwrap_or(value);
+
+    split_list(value)
+        .into_iter()
+        .map(|item| unquote(item).to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+// Splits on commas that aren't inside quotes and trims each item. Quotes are
+// kept so callers can decide whether to strip them.
+fn split_list(list: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut start = 0;
+
+    for (i, c) in list.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if opens_quote(list, i, c) => quote = Some(c),
+            None if c == ',' => {
+                items.push(list[start..i].trim());
+                start = i + 1;
+            }
+            None => {}
+        }
+    }
+    items.push(list[start..].trim());
+
+    items
+}
+
+// Splits `region default "N/A"` into the column and its default. A
+// `default` inside quotes is part of the name.
+fn split_column_default(item: &str) -> (&str, Option<&str>) {
+    let mut quote: Option<char> = None;
+    for (i, c) in item.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if opens_quote(item, i, c) => quote = Some(c),
+            None if c.is_whitespace() => {
+                let rest = item[i..].trim_start();
+                let word = rest.split_whitespace().next().unwrap_or("");
+                if word.eq_ignore_ascii_case("default") {
+                    return (item[..i].trim(), Some(rest[word.len()..].trim()));
+                }
+            }
+            None => {}
+        }
+    }
+    (item, None)
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote) {
+            return &value[1..value.len() - 1];
+        }
+    }
+    value
+}
+
+// Parses `since <date> on <column>` and `until <date> on <column>`, which
+// together describe the half-open range `[since, until)`.
+fn parse_date_bound(words: &[&str], today: NaiveDate) -> Result<Filter, String> {
+    let stage = words.join(" ");
+    let on_index = words
+        .iter()
+        .position(|w| w.eq_ignore_ascii_case("on"))
+        .ok_or_else(|| format!("Missing 'on <column>' in: {}", stage))?;
+    if on_index < 2 || on_index + 2 != words.len() {
+        return Err(format!(
+            "Expected '{} <date> on <column>': {}",
+            words[0], stage
+        ));
+    }
+
+    let date_str = words[1..on_index].join(" ");
+    let relative = resolve_relative_date(&date_str, today);
+    let date = relative
+        .or_else(|| parse_date(&date_str))
+        .ok_or_else(|| format!("Invalid date '{}' in: {}", date_str, stage))?;
+
+    Ok(Filter {
+        columns: vec![words[on_index + 1].to_string()],
+        operator: words[0].to_lowercase(),
+        value: date.format("%Y-%m-%d").to_string(),
+        value_set: None,
+        cast: None,
+        range: None,
+        regex: None,
+        modulus: None,
+        quantile: None,
+        decimal_comma: false,
+        relative: relative.map(|_| date_str),
+    })
+}
+
+// Turns `today`, `yesterday`, `today-Nd` and `today-Nw` (or `+N`) into a
+// concrete date. Anything else is not a relative date and yields `None`.
+fn resolve_relative_date(token: &str, today: NaiveDate) -> Option<NaiveDate> {
+    let token = token.to_lowercase();
+    if token == "yesterday" {
+        return today.pred_opt();
+    }
+
+    let offset = token.strip_prefix("today")?;
+    if offset.is_empty() {
+        return Some(today);
+    }
+
+    let (sign, amount) = if let Some(rest) = offset.strip_prefix('-') {
+        (-1, rest)
+    } else {
+        (1, offset.strip_prefix('+')?)
+    };
+    let (count, unit_days) = if let Some(count) = amount.strip_suffix('d') {
+        (count, 1)
+    } else {
+        (amount.strip_suffix('w')?, 7)
+    };
+    let count: i64 = count.parse().ok()?;
+    let days = count.checked_mul(sign * unit_days)?;
+
+    today.checked_add_signed(chrono::Duration::try_days(days)?)
+}
+
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    let value = value.trim();
+    let date_part = timestamp_date(value).unwrap_or(value);
+    NaiveDate::parse_from_str(date_part, "%Y-%m-%d")
+        .ok()
+        .or_else(|| {
+            // Slashes are read US-style, month first.
+            [
+                "%Y/%m/%d",
+                "%m/%d/%Y",
+                "%d.%m.%Y",
+                "%d-%b-%Y",
+                "%d %b %Y",
+                "%b %d, %Y",
+            ]
+            .iter()
+            .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+        })
+}
+
+// Timestamps like `2024-01-05T10:00:00` or `2024-01-05 10:00` compare by
+// their date part. Anything else after the date, as in `2024-01-05-A`, means
+// the value isn't a timestamp.
+fn timestamp_date(value: &str) -> Option<&str> {
+    let time = value.get(10..)?.strip_prefix(['T', ' '])?;
+    let time = time.strip_suffix('Z').unwrap_or(time);
+    let is_time = ["%H:%M:%S%.f", "%H:%M"]
+        .iter()
+        .any(|format| chrono::NaiveTime::parse_from_str(time, format).is_ok());
+    (is_time || chrono::DateTime::parse_from_rfc3339(value).is_ok()).then(|| &value[..10])
+}
+
+// Like `parse_date`, but keeps the time of day so timestamps on the same date
+// still order correctly. Plain dates are taken as midnight.
+fn parse_datetime(value: &str) -> Option<NaiveDateTime> {
+    let value = value.trim();
+    [
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+    .or_else(|| parse_date(value).and_then(|date| date.and_hms_opt(0, 0, 0)))
+}
+
+/// Runs the query against its table file. The result's first row is the header.
+pub fn execute_query(query: &Query) -> Result<Vec<Vec<String>>, Error> {
+    let (header_names, rows) = read_table(&query.table_name, query)?;
+    execute_query_on(query, header_names, rows)
+}
+
+/// Parses and runs `query` against CSV read from `reader`, e.g.
+/// `run_query("people >> age greater than 30", csv.as_bytes())`. The table
+/// name in the query is ignored, and may be left out: `>> show name`. The
+/// delimiter is detected from the data. Warnings are dropped; to see them,
+/// parse the query and read `Query::notices` after `execute_query_on`.
+pub fn run_query<R: Read>(query: &str, mut reader: R) -> Result<Vec<Vec<String>>, Error> {
+    let mut query = parse_query(query, Some(STDIN_TABLE), false, &HashMap::new())?;
+    query.delimiter = None;
+    let mut input = Vec::new();
+    reader.read_to_end(&mut input)?;
+    let (header_names, rows) = read_csv_bytes(&input, &query, "input")?;
+    execute_query_on(&query, header_names, rows)
+}
+
+/// Runs the query against rows the caller already has, e.g. from memory or
+/// another data source, instead of reading `query.table_name`. Joins still
+/// read their tables from disk.
+pub fn execute_query_on(
+    query: &Query,
+    mut header_names: Vec<String>,
+    mut rows: Vec<Vec<String>>,
+) -> Result<Vec<Vec<String>>, Error> {
+    let mut header_map = build_header_map(&header_names);
+
+    // This is about the file itself; a filter matching nothing is still fine.
+    if query.fail_if_empty && rows.is_empty() {
+        return Err(format!("Table '{}' has no data rows", query.table_name).into());
+    }
+
+    // With joins every column can also be named `table.column`, e.g.
+    // `employees.name`. These map a qualified name to the column's real name.
+    let mut qualified: Vec<(String, String)> = Vec::new();
+    if !query.joins.is_empty() {
+        let table = std::path::Path::new(&query.table_name)
+            .file_stem()
+            .map_or_else(
+                || query.table_name.clone(),
+                |stem| stem.to_string_lossy().into_owned(),
+            );
+        qualified.extend(
+            header_names
+                .iter()
+                .map(|name| (format!("{}.{}", table, name), name.clone())),
+        );
+    }
+
+    for join in &query.joins {
+        (header_names, rows) = apply_join(header_names, rows, join, query, &mut qualified)?;
+        header_map = build_header_map(&header_names);
+        add_qualified_names(&mut header_map, &qualified);
+    }
+
+    for reshape in &query.reshapes {
+        (header_names, rows) = apply_reshape(header_names, rows, reshape, query)?;
+        header_map = build_header_map(&header_names);
+        add_qualified_names(&mut header_map, &qualified);
+    }
+
+    if query.list_columns {
+        let mut result = vec![vec!["column".to_string()]];
+        result.extend(header_names.into_iter().map(|name| vec![name]));
+        return Ok(result);
+    }
+
+    if query.strict {
+        let filters = query
+            .filters
+            .iter()
+            .chain(query.or_filters.iter().flatten().flatten());
+        for filter in filters {
+            check_value_set_exists(&rows, filter, &header_map)?;
+        }
+    }
+    // `above pNN` / `below pNN` need the column's distribution first.
+    let filters = resolve_quantiles(&query.filters, &rows, &header_map)?;
+    let or_filters = query
+        .or_filters
+        .iter()
+        .map(|branches| {
+            branches
+                .iter()
+                .map(|branch| resolve_quantiles(branch, &rows, &header_map))
+                .collect::<Result<Vec<_>, String>>()
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    rows = apply_filters(rows, &filters, &header_map)?;
+    rows = apply_or_filters(rows, &or_filters, &header_map)?;
+
+    if let Some(latest_per) = &query.latest_per {
+        rows = apply_latest_per(rows, latest_per, &header_map)?;
+    }
+
+    // Grouping replaces the table, so the stages after it (sort, take, show)
+    // work on the grouped columns.
+    if let Some(group_col) = &query.group_column {
+        (header_names, rows) = apply_group(rows, group_col, query, &header_map)?;
+        header_map = build_header_map(&header_names);
+    } else if let Some(column) = &query.minmax_column {
+        header_names = vec!["min".to_string(), "max".to_string()];
+        rows = vec![date_range(&rows, column, &header_map, &query.notices)?];
+        header_map = build_header_map(&header_names);
+    } else if let Some(aggregate) = &query.aggregate {
+        let value = compute_aggregate(aggregate, &rows, query, &header_map)?;
+        header_names = vec![aggregate_label(aggregate)];
+        rows = vec![vec![value]];
+        header_map = build_header_map(&header_names);
+    }
+
+    // Before sorting and `take`, so those see the deduplicated rows. Rows are
+    // compared on the columns `show` keeps.
+    if query.distinct {
+        rows = apply_distinct(
+            rows,
+            query.columns.as_deref(),
+            &query.column_defaults,
+            &header_map,
+        )?;
+    }
+
+    if !query.sort_keys.is_empty() {
+        apply_sort(
+            &mut rows,
+            &query.sort_keys,
+            query.sort_precision,
+            query.decimal_comma,
+            &header_map,
+        )?;
+    }
+
+    if let Some(cumsum) = &query.cumsum {
+        (header_names, rows) = apply_cumsum(header_names, rows, cumsum, query, &header_map)?;
+        header_map = build_header_map(&header_names);
+        add_qualified_names(&mut header_map, &qualified);
+    }
+
+    if let Some(limit) = query.limit {
+        rows.truncate(limit);
+    }
+    let final_rows = if let Some(columns) = &query.columns {
+        select_columns(rows, columns, &query.column_defaults, &header_map)?
+    } else {
+        let mut result = vec![header_names];
+        result.extend(rows);
+        result
+    };
+
+    Ok(final_rows)
+}
+
+// `auto` becomes `None`, to be detected per file.
+pub fn parse_delimiter(value: &str) -> Result<Option<u8>, String> {
+    match value {
+        "auto" => Ok(None),
+        "tab" | "\\t" => Ok(Some(b'\t')),
+        _ if value.len() == 1 && value.is_ascii() => Ok(Some(value.as_bytes()[0])),
+        _ => Err(format!(
+            "Invalid delimiter '{}': use a single character, tab or auto",
+            value
+        )),
+    }
+}
+
+pub fn table_delimiter(path: &str, query: &Query) -> Result<u8, Error> {
+    if let Some(delimiter) = query.delimiter {
+        return Ok(delimiter);
+    }
+
+    let mut sample = Vec::new();
+    std::fs::File::open(path)?
+        .take(64 * 1024)
+        .read_to_end(&mut sample)?;
+    let delimiter = sniff_delimiter(&String::from_utf8_lossy(&sample));
+    if query.verbose {
+        query.notices.info(format!(
+            "Detected delimiter {:?} for {}",
+            delimiter as char, path
+        ));
+    }
+    Ok(delimiter)
+}
+
+// Picks the candidate that splits the first lines into the most consistent
+// number of fields, preferring more fields on a tie. Falls back to a comma.
+fn sniff_delimiter(sample: &str) -> u8 {
+    let lines: Vec<&str> = sample
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .take(10)
+        .collect();
+
+    let mut best = (b',', 0.0, 0);
+    for candidate in [b',', b'\t', b';', b'|'] {
+        let counts: Vec<usize> = lines
+            .iter()
+            .map(|line| count_unquoted(line, candidate as char))
+            .collect();
+        let Some(&first) = counts.first() else {
+            continue;
+        };
+        if first == 0 {
+            continue;
+        }
+        let consistency =
+            counts.iter().filter(|&&c| c == first).count() as f64 / counts.len() as f64;
+        if consistency > best.1 || (consistency == best.1 && first > best.2) {
+            best = (candidate, consistency, first);
+        }
+    }
+
+    best.0
+}
+
+fn count_unquoted(line: &str, delimiter: char) -> usize {
+    let mut in_quotes = false;
+    let mut count = 0;
+    for c in line.chars() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == delimiter && !in_quotes {
+            count += 1;
+        }
+    }
+    count
+}
+
+// A path with glob characters, e.g. `logs_*`, reads every matching file as one
+// table. The files must share the same header. With `--shard`, only the query's
+// own table is cut down to its shard; joined tables are read in full.
+pub fn read_table(path: &str, query: &Query) -> Result<Table, Error> {
+    let (header_names, rows) = if is_glob(path) {
+        read_table_union(path, query)?
+    } else {
+        read_table_file(path, query)?
+    };
+
+    match query.shard {
+        Some((index, count)) if path == query.table_name => {
+            // Round-robin: row `i` belongs to shard `i % count + 1`.
+            let rows = rows
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| i % count == index - 1)
+                .map(|(_, row)| row)
+                .collect();
+            Ok((header_names, rows))
+        }
+        _ => Ok((header_names, rows)),
+    }
+}
+
+/// Parses `--shard K/N` into `(K, N)`, with `1 <= K <= N`.
+pub fn parse_shard(value: &str) -> Result<(usize, usize), Error> {
+    let invalid = || Error::Parse(format!("Invalid shard '{}': expected K/N, e.g. 2/5", value));
+    let (index, count) = value.split_once('/').ok_or_else(invalid)?;
+    let index: usize = index.trim().parse().map_err(|_| invalid())?;
+    let count: usize = count.trim().parse().map_err(|_| invalid())?;
+    if index == 0 || index > count {
+        return Err(invalid());
+    }
+    Ok((index, count))
+}
+
+fn read_table_union(path: &str, query: &Query) -> Result<Table, Error> {
+    let mut paths = glob::glob(path)?.collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|file| {
+        let name = file.file_name().map(|n| n.to_string_lossy().into_owned());
+        !query.exclude.iter().any(|pattern| {
+            pattern.matches_path(file) || name.as_deref().is_some_and(|n| pattern.matches(n))
+        })
+    });
+    paths.sort();
+
+    // Files are read one at a time, each closed before the next is opened, so
+    // a pattern matching thousands of files never runs out of file handles.
+    let mut union: Option<(String, Table)> = None;
+    for file in &paths {
+        let file = file.to_string_lossy();
+        let (header_names, mut rows) = read_table_file(&file, query)?;
+        if query.source_column.is_some() {
+            for row in rows.iter_mut() {
+                row.push(file.to_string());
+            }
+        }
+        match &mut union {
+            None => union = Some((file.to_string(), (header_names, rows))),
+            Some((first, (union_headers, union_rows))) => {
+                if *union_headers != header_names {
+                    return Err(format!("'{}' has different columns than '{}'", file, first).into());
+                }
+                union_rows.extend(rows);
+            }
+        }
+    }
+
+    let Some((_, (mut header_names, mut rows))) = union else {
+        return Err(format!("No files match '{}'", path).into());
+    };
+    if query.union_distinct {
+        // Keeps the first occurrence of each row; the source column is not
+        // part of the comparison.
+        let width = header_names.len();
+        let mut seen: HashSet<Vec<String>> = HashSet::new();
+        rows.retain(|row| seen.insert(row[..width.min(row.len())].to_vec()));
+    }
+    if let Some(source_column) = &query.source_column {
+        if header_names.contains(source_column) {
+            return Err(format!("'{}' already has a '{}' column", path, source_column).into());
+        }
+        header_names.push(source_column.clone());
+    }
+    Ok((header_names, rows))
+}
+
+pub fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+fn read_table_file(path: &str, query: &Query) -> Result<Table, Error> {
+    let table = if path == STDIN_TABLE {
+        read_stdin_table(query)?
+    } else if let Some((archive, entry)) = split_archive_entry(path) {
+        read_zip_entry(archive, entry, query)?
+    } else if path.ends_with(".json") {
+        return read_json_table(path, query);
+    } else {
+        let file = std::fs::File::open(path)?;
+        let delimiter = table_delimiter(path, query)?;
+        if query.lossy_utf8 {
+            read_csv_lossy(file, delimiter, path, &query.notices)?
+        } else {
+            read_csv(file, delimiter)?
+        }
+    };
+    merge_header_rows(table, query)
+}
+
+// With `--header-rows N`, the header plus the next N - 1 rows name the
+// columns: each column's non-empty parts joined by `--header-join`. A blank
+// cell in the upper rows continues the group to its left, so `Q1,,Q2,` over
+// `jan,feb,apr,may` gives `Q1 / feb`.
+fn merge_header_rows(table: Table, query: &Query) -> Result<Table, Error> {
+    let (header_names, mut rows) = table;
+    if query.header_rows <= 1 {
+        return Ok((header_names, rows));
+    }
+    if rows.len() < query.header_rows - 1 {
+        return Err(format!(
+            "Table '{}' has fewer than {} header rows",
+            query.table_name, query.header_rows
+        )
+        .into());
+    }
+
+    let mut header_lines = vec![header_names];
+    header_lines.extend(rows.drain(..query.header_rows - 1));
+    let last = header_lines.len() - 1;
+    for line in &mut header_lines[..last] {
+        let mut group = String::new();
+        for cell in line.iter_mut() {
+            if cell.trim().is_empty() {
+                cell.clone_from(&group);
+            } else {
+                group.clone_from(cell);
+            }
+        }
+    }
+
+    let width = header_lines
+        .iter()
+        .map(|line| line.len())
+        .max()
+        .unwrap_or(0);
+    let merged = (0..width)
+        .map(|i| {
+            header_lines
+                .iter()
+                .filter_map(|line| line.get(i).map(|cell| cell.trim()))
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<&str>>()
+                .join(&query.header_join)
+        })
+        .collect();
+    Ok((merged, rows))
+}
+
+// Stdin is read in full up front: it can only be read once, and the delimiter
+// may have to be sniffed before the header is parsed.
+fn read_stdin_table(query: &Query) -> Result<Table, Error> {
+    let mut input = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut input)?;
+    if input.iter().all(u8::is_ascii_whitespace) {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "No input: the table is read from stdin, but nothing was piped in",
+        )));
+    }
+
+    read_csv_bytes(&input, query, "stdin")
+}
+
+// `data.zip!employees.csv`: the archive path and the entry inside it.
+fn split_archive_entry(path: &str) -> Option<(&str, &str)> {
+    let (archive, entry) = path.split_once('!')?;
+    archive
+        .to_lowercase()
+        .ends_with(".zip")
+        .then_some((archive, entry))
+}
+
+fn read_zip_entry(archive: &str, entry: &str, query: &Query) -> Result<Table, Error> {
+    let mut zip = zip::ZipArchive::new(std::fs::File::open(archive)?)
+        .map_err(|e| Error::Io(std::io::Error::other(format!("{}: {}", archive, e))))?;
+    let mut file = match zip.by_name(entry) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => {
+            return Err(format!("No entry '{}' in '{}'", entry, archive).into());
+        }
+        Err(e) => {
+            return Err(Error::Io(std::io::Error::other(format!(
+                "{}!{}: {}",
+                archive, entry, e
+            ))));
+        }
+    };
+
+    let mut input = Vec::new();
+    file.read_to_end(&mut input)?;
+    read_csv_bytes(&input, query, &format!("{}!{}", archive, entry))
+}
+
+// A CSV table already read into memory; `source` names it in warnings.
+fn read_csv_bytes(input: &[u8], query: &Query, source: &str) -> Result<Table, Error> {
+    let delimiter = match query.delimiter {
+        Some(delimiter) => delimiter,
+        None => sniff_delimiter(&String::from_utf8_lossy(
+            &input[..input.len().min(64 * 1024)],
+        )),
+    };
+    if query.lossy_utf8 {
+        return read_csv_lossy(input, delimiter, source, &query.notices);
+    }
+    read_csv(input, delimiter)
+}
+
+/// Reads a CSV table, header first, from any reader.
+pub fn read_csv<R: Read>(reader: R, delimiter: u8) -> Result<Table, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(reader);
+    let header_names = header_names(&mut reader)?;
+
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for result in reader.records() {
+        let record = result?;
+        let row: Vec<String> = record.iter().map(|field| field.to_string()).collect();
+        rows.push(row);
+    }
+
+    Ok((header_names, rows))
+}
+
+// Like `read_csv`, but invalid UTF-8 is replaced with U+FFFD instead of
+// failing the read, with a warning naming `source` if anything was replaced.
+fn read_csv_lossy<R: Read>(
+    reader: R,
+    delimiter: u8,
+    source: &str,
+    notices: &Notices,
+) -> Result<Table, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(reader);
+
+    let mut replaced = 0;
+    let mut to_strings = |record: &csv::ByteRecord| -> Vec<String> {
+        record
+            .iter()
+            .map(|field| match String::from_utf8_lossy(field) {
+                std::borrow::Cow::Borrowed(text) => text.to_string(),
+                std::borrow::Cow::Owned(text) => {
+                    replaced += 1;
+                    text
+                }
+            })
+            .collect()
+    };
+
+    let header_names = to_strings(reader.byte_headers()?)
+        .into_iter()
+        .map(|h| h.trim().to_string())
+        .collect();
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for result in reader.byte_records() {
+        rows.push(to_strings(&result?));
+    }
+
+    if replaced > 0 {
+        notices.warn(format!(
+            "replaced invalid UTF-8 in {} fields of '{}'",
+            replaced, source
+        ));
+    }
+    Ok((header_names, rows))
+}
+
+/// Just the header of a CSV table file.
+pub fn read_header(path: &str, query: &Query) -> Result<Vec<String>, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(table_delimiter(path, query)?)
+        .from_path(path)?;
+    Ok(header_names(&mut reader)?)
+}
+
+// A JSON array of objects, one row per object. `--json-path` selects an
+// array nested inside the document first. Nested objects become `outer.inner`
+// columns; columns are ordered by first appearance and missing keys are empty.
+fn read_json_table(path: &str, query: &Query) -> Result<Table, Error> {
+    let document: serde_json::Value =
+        serde_json::from_reader(std::io::BufReader::new(std::fs::File::open(path)?))?;
+
+    let mut selected = &document;
+    if let Some(json_path) = &query.json_path {
+        for key in json_path.split('.').filter(|k| !k.is_empty()) {
+            let next = match selected {
+                serde_json::Value::Array(items) => {
+                    key.parse::<usize>().ok().and_then(|i| items.get(i))
+                }
+                _ => selected.get(key),
+            };
+            selected =
+                next.ok_or_else(|| format!("JSON path '{}' not found in {}", json_path, path))?;
+        }
+    }
+    let serde_json::Value::Array(items) = selected else {
+        return Err(match &query.json_path {
+            Some(json_path) => format!("JSON path '{}' in {} is not an array", json_path, path),
+            None => format!("{} is not a JSON array; pick one with --json-path", path),
+        }
+        .into());
+    };
+
+    let mut header_names: Vec<String> = Vec::new();
+    let mut header_map: HashMap<String, usize> = HashMap::new();
+    let mut records: Vec<Vec<(usize, String)>> = Vec::new();
+    for item in items {
+        let mut fields = Vec::new();
+        flatten_json(item, "", &mut fields);
+        let record = fields
+            .into_iter()
+            .map(|(name, value)| {
+                let index = *header_map.entry(name.clone()).or_insert_with(|| {
+                    header_names.push(name);
+                    header_names.len() - 1
+                });
+                (index, value)
+            })
+            .collect();
+        records.push(record);
+    }
+
+    let rows = records
+        .into_iter()
+        .map(|record| {
+            let mut row = vec![String::new(); header_names.len()];
+            for (index, value) in record {
+                row[index] = value;
+            }
+            row
+        })
+        .collect();
+    Ok((header_names, rows))
+}
+
+// Collects `(column, cell)` pairs. Arrays are kept as JSON text and nulls
+// become empty cells; a bare value outside an object goes in a `value` column.
+fn flatten_json(value: &serde_json::Value, prefix: &str, fields: &mut Vec<(String, String)>) {
+    let name = if prefix.is_empty() { "value" } else { prefix };
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, inner) in map {
+                let column = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_json(inner, &column, fields);
+            }
+        }
+        serde_json::Value::Null => fields.push((name.to_string(), String::new())),
+        serde_json::Value::String(text) => fields.push((name.to_string(), text.clone())),
+        other => fields.push((name.to_string(), other.to_string())),
+    }
+}
+
+// Header names with surrounding whitespace removed, so `age` finds a column
+// written as `"age "`.
+fn header_names<R: Read>(reader: &mut csv::Reader<R>) -> Result<Vec<String>, csv::Error> {
+    Ok(reader
+        .headers()?
+        .iter()
+        .map(|h| h.trim().to_string())
+        .collect())
+}
+
+// Adds the `table.column` names of columns that are still there and aren't
+// shadowed by a real column of that name.
+fn add_qualified_names(header_map: &mut HashMap<String, usize>, qualified: &[(String, String)]) {
+    for (alias, name) in qualified {
+        if !header_map.contains_key(alias)
+            && let Some(&index) = header_map.get(name)
+        {
+            header_map.insert(alias.clone(), index);
+        }
+    }
+}
+
+// Inner join against another table. The right side's key columns are dropped
+// when they share the left side's names; other right columns whose names are
+// already taken get a `table.` prefix. Every right column's `table.column`
+// name is added to `qualified`.
+fn apply_join(
+    header_names: Vec<String>,
+    rows: Vec<Vec<String>>,
+    join: &Join,
+    query: &Query,
+    qualified: &mut Vec<(String, String)>,
+) -> Result<Table, Error> {
+    let (right_headers, right_rows) = read_table(&join.table_name, query)?;
+    let left_map = build_header_map(&header_names);
+    let right_map = build_header_map(&right_headers);
+
+    let key_indices = |keys: &[String], map: &HashMap<String, usize>| {
+        keys.iter()
+            .map(|k| {
+                map.get(k)
+                    .copied()
+                    .ok_or_else(|| format!("Column '{}' not found", k))
+            })
+            .collect::<Result<Vec<usize>, String>>()
+    };
+    let left_keys = key_indices(&join.left_keys, &left_map)?;
+    let right_keys = key_indices(&join.right_keys, &right_map)?;
+
+    let key_of = |row: &[String], indices: &[usize]| -> Vec<String> {
+        indices
+            .iter()
+            .map(|&i| row.get(i).cloned().unwrap_or_default())
+            .collect()
+    };
+
+    let mut index: HashMap<Vec<String>, Vec<usize>> = HashMap::new();
+    for (i, row) in right_rows.iter().enumerate() {
+        index.entry(key_of(row, &right_keys)).or_default().push(i);
+    }
+
+    let kept_right: Vec<usize> = (0..right_headers.len())
+        .filter(|i| !(right_keys.contains(i) && join.left_keys.contains(&right_headers[*i])))
+        .collect();
+
+    let mut joined_headers = header_names.clone();
+    for &i in &kept_right {
+        let name = &right_headers[i];
+        let qualified_name = format!("{}.{}", join.table, name);
+        if header_names.contains(name) {
+            joined_headers.push(qualified_name);
+        } else {
+            joined_headers.push(name.clone());
+            qualified.push((qualified_name, name.clone()));
+        }
+    }
+    // Dropped key columns are the same values as the left keys.
+    for (left, right) in join.left_keys.iter().zip(&join.right_keys) {
+        if left == right {
+            qualified.push((format!("{}.{}", join.table, right), left.clone()));
+        }
+    }
+
+    let mut joined_rows = Vec::new();
+    for row in rows {
+        let Some(matches) = index.get(&key_of(&row, &left_keys)) else {
+            continue;
+        };
+        for &m in matches {
+            let mut joined = row.clone();
+            joined.resize(header_names.len(), String::new());
+            joined.extend(
+                kept_right
+                    .iter()
+                    .map(|&i| right_rows[m].get(i).cloned().unwrap_or_default()),
+            );
+            joined_rows.push(joined);
+        }
+    }
+
+    Ok((joined_headers, joined_rows))
+}
+
+fn apply_reshape(
+    header_names: Vec<String>,
+    rows: Vec<Vec<String>>,
+    reshape: &Reshape,
+    query: &Query,
+) -> Result<Table, String> {
+    match reshape {
+        Reshape::Split(split) => apply_split(header_names, rows, split, query.split_remainder),
+        Reshape::Merge(merge) => apply_merge(header_names, rows, merge, query.merge_drop),
+        Reshape::Hash(hash) => apply_hash(header_names, rows, hash),
+    }
+}
+
+// Appends the SHA-256 of the `hash` columns as lowercase hex. Values are
+// joined with a unit separator, so `ab` + `c` and `a` + `bc` hash differently.
+#[cfg(feature = "hash")]
+fn apply_hash(
+    mut header_names: Vec<String>,
+    mut rows: Vec<Vec<String>>,
+    hash: &ColumnHash,
+) -> Result<Table, String> {
+    use sha2::{Digest, Sha256};
+
+    let indices = hash
+        .columns
+        .iter()
+        .map(|column| {
+            header_names
+                .iter()
+                .position(|h| h == column)
+                .ok_or_else(|| format!("Column '{}' not found", column))
+        })
+        .collect::<Result<Vec<usize>, String>>()?;
+    if header_names.contains(&hash.into) {
+        return Err(format!("Hash column '{}' already exists", hash.into));
+    }
+
+    for row in rows.iter_mut() {
+        let values: Vec<&str> = indices
+            .iter()
+            .map(|&i| row.get(i).map(|v| v.as_str()).unwrap_or(""))
+            .collect();
+        let digest = Sha256::digest(values.join("\u{1f}").as_bytes());
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        row.push(hex);
+    }
+    header_names.push(hash.into.clone());
+
+    Ok((header_names, rows))
+}
+
+#[cfg(not(feature = "hash"))]
+fn apply_hash(_: Vec<String>, _: Vec<Vec<String>>, _: &ColumnHash) -> Result<Table, String> {
+    Err("The hash stage needs flexiql built with the 'hash' feature".to_string())
+}
+
+// Appends the columns of a `split` stage. Values with fewer parts than names
+// leave the remaining columns empty; extra parts are dropped, or kept joined
+// in the last column with `keep_remainder`.
+fn apply_split(
+    mut header_names: Vec<String>,
+    mut rows: Vec<Vec<String>>,
+    split: &ColumnSplit,
+    keep_remainder: bool,
+) -> Result<Table, String> {
+    let index = header_names
+        .iter()
+        .position(|h| *h == split.column)
+        .ok_or_else(|| format!("Column '{}' not found", split.column))?;
+    if let Some(name) = split.into.iter().find(|name| header_names.contains(name)) {
+        return Err(format!("Split column '{}' already exists", name));
+    }
+
+    let count = split.into.len();
+    for row in rows.iter_mut() {
+        let value = row.get(index).cloned().unwrap_or_default();
+        let mut parts: Vec<String> = if keep_remainder {
+            value
+                .splitn(count, split.delimiter.as_str())
+                .map(|p| p.to_string())
+                .collect()
+        } else {
+            value
+                .split(split.delimiter.as_str())
+                .take(count)
+                .map(|p| p.to_string())
+                .collect()
+        };
+        parts.resize(count, String::new());
+        row.extend(parts);
+    }
+    header_names.extend(split.into.iter().cloned());
+
+    Ok((header_names, rows))
+}
+
+// Appends a column joining the `merge` columns with its separator, and with
+// `drop_sources` removes the columns that went into it.
+fn apply_merge(
+    mut header_names: Vec<String>,
+    mut rows: Vec<Vec<String>>,
+    merge: &ColumnMerge,
+    drop_sources: bool,
+) -> Result<Table, String> {
+    let indices = merge
+        .columns
+        .iter()
+        .map(|column| {
+            header_names
+                .iter()
+                .position(|h| h == column)
+                .ok_or_else(|| format!("Column '{}' not found", column))
+        })
+        .collect::<Result<Vec<usize>, String>>()?;
+    if header_names.contains(&merge.into) && !(drop_sources && merge.columns.contains(&merge.into))
+    {
+        return Err(format!("Merge column '{}' already exists", merge.into));
+    }
+
+    for row in rows.iter_mut() {
+        let values: Vec<&str> = indices
+            .iter()
+            .map(|&i| row.get(i).map(|v| v.as_str()).unwrap_or(""))
+            .collect();
+        let merged = values.join(&merge.separator);
+        row.push(merged);
+    }
+    header_names.push(merge.into.clone());
+
+    if drop_sources {
+        let keep: Vec<bool> = (0..header_names.len())
+            .map(|i| !indices.contains(&i))
+            .collect();
+        let retain = |values: &mut Vec<String>| {
+            let mut flags = keep.iter();
+            values.retain(|_| *flags.next().unwrap_or(&true));
+        };
+        retain(&mut header_names);
+        rows.iter_mut().for_each(retain);
+    }
+
+    Ok((header_names, rows))
+}
+
+/// Applies the per-row stages of the query (split / merge, filters and column
+/// selection) to rows read without their header, such as rows appended to a
+/// table after it was first queried. `file_headers` is the table's header.
+pub fn filter_appended_rows(
+    query: &Query,
+    file_headers: &[String],
+    mut rows: Vec<Vec<String>>,
+) -> Result<Vec<Vec<String>>, Error> {
+    let mut header_names = file_headers.to_vec();
+    for reshape in &query.reshapes {
+        (header_names, rows) = apply_reshape(header_names, rows, reshape, query)?;
+    }
+    let header_map = build_header_map(&header_names);
+
+    rows = apply_filters(rows, &query.filters, &header_map)?;
+    rows = apply_or_filters(rows, &query.or_filters, &header_map)?;
+    if let Some(columns) = &query.columns {
+        rows = select_columns(rows, columns, &query.column_defaults, &header_map)?
+            .into_iter()
+            .skip(1)
+            .collect();
+    }
+    Ok(rows)
+}
+
+/// Maps each column name to its index.
+pub fn build_header_map(header_names: &[String]) -> HashMap<String, usize> {
+    let mut header_map = HashMap::new();
+    for (i, header) in header_names.iter().enumerate() {
+        header_map.insert(header.trim().to_string(), i);
+    }
+    header_map
+}
+
+// Keeps the rows matching every filter. Filters are checked cheapest first so
+// a row failing a quick equality test never reaches the more expensive ones;
+// the result is the same in any order.
+fn apply_filters(
+    rows: Vec<Vec<String>>,
+    filters: &[Filter],
+    header_map: &HashMap<String, usize>,
+) -> Result<Vec<Vec<String>>, String> {
+    filter_rows(rows, filters, header_map, row_matches)
+}
+
+// Keeps the rows for which `matches` holds for every filter, checking the
+// cheapest filters first so a row fails as early as possible.
+fn filter_rows(
+    rows: Vec<Vec<String>>,
+    filters: &[Filter],
+    header_map: &HashMap<String, usize>,
+    mut matches: impl FnMut(&[String], &Filter, &[usize]) -> bool,
+) -> Result<Vec<Vec<String>>, String> {
+    if filters.is_empty() {
+        return Ok(rows);
+    }
+
+    let mut ordered = filters
+        .iter()
+        .map(|filter| Ok((filter, filter_column_indices(filter, header_map)?)))
+        .collect::<Result<Vec<_>, String>>()?;
+    ordered.sort_by_key(|(filter, columns)| filter_cost(filter) * columns.len());
+
+    let filtered_rows: Vec<Vec<String>> = rows
+        .into_iter()
+        .filter(|row| {
+            ordered
+                .iter()
+                .all(|(filter, columns)| matches(row, filter, columns))
+        })
+        .collect();
+
+    Ok(filtered_rows)
+}
+
+// Keeps the rows passing every `or` stage: all filters of some branch match.
+fn apply_or_filters(
+    mut rows: Vec<Vec<String>>,
+    stages: &[Vec<Vec<Filter>>],
+    header_map: &HashMap<String, usize>,
+) -> Result<Vec<Vec<String>>, String> {
+    for branches in stages {
+        let indexed = branches
+            .iter()
+            .map(|branch| {
+                branch
+                    .iter()
+                    .map(|filter| Ok((filter, filter_column_indices(filter, header_map)?)))
+                    .collect::<Result<Vec<_>, String>>()
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        rows.retain(|row| {
+            indexed.iter().any(|branch| {
+                branch
+                    .iter()
+                    .all(|(filter, columns)| row_matches(row, filter, columns))
+            })
+        });
+    }
+    Ok(rows)
+}
+
+// Copies the filters, setting the value of each quantile filter to the
+// quantile of the numbers in its columns, interpolated between the closest
+// ranks. Without any numbers the filter keeps its `pNN` value and matches
+// nothing.
+fn resolve_quantiles(
+    filters: &[Filter],
+    rows: &[Vec<String>],
+    header_map: &HashMap<String, usize>,
+) -> Result<Vec<Filter>, String> {
+    let mut resolved = filters.to_vec();
+    for filter in resolved.iter_mut() {
+        let Some(quantile) = filter.quantile else {
+            continue;
+        };
+        let column_indices = filter_column_indices(filter, header_map)?;
+        let mut numbers: Vec<f64> = rows
+            .iter()
+            .flat_map(|row| column_indices.iter().filter_map(|&i| row.get(i)))
+            .filter_map(|cell| parse_number(cell, filter.decimal_comma))
+            .filter(|n| n.is_finite())
+            .collect();
+        if numbers.is_empty() {
+            continue;
+        }
+        numbers.sort_by(|a, b| a.total_cmp(b));
+
+        let rank = quantile * (numbers.len() - 1) as f64;
+        let (low, high) = (rank.floor() as usize, rank.ceil() as usize);
+        let threshold = numbers[low] + (numbers[high] - numbers[low]) * (rank - low as f64);
+        filter.value = threshold.to_string();
+    }
+    Ok(resolved)
+}
+
+fn row_matches(row: &[String], filter: &Filter, column_indices: &[usize]) -> bool {
+    column_indices.iter().any(|&i| match row.get(i) {
+        Some(cell_value) => check_condition(cell_value, filter),
+        None => false,
+    })
+}
+
+// Rough relative cost of checking one cell. Only the order matters.
+fn filter_cost(filter: &Filter) -> usize {
+    match filter.operator.as_str() {
+        "in" | "not in" | "is" => 1,
+        "between" | "not between" | "above" | "below" => 2,
+        "equals" | "=" | "==" | "not equals" | "!=" | "greater" | ">" | "less" | "<" | ">="
+        | "<="
+            if filter.cast.is_none() =>
+        {
+            2
+        }
+        "contains" | "starts" | "ends" => 3,
+        // Date parsing on every cell.
+        _ => 4,
+    }
+}
+
+fn filter_column_indices(
+    filter: &Filter,
+    header_map: &HashMap<String, usize>,
+) -> Result<Vec<usize>, String> {
+    filter
+        .columns
+        .iter()
+        .map(|column| {
+            header_map
+                .get(column)
+                .copied()
+                .ok_or_else(|| format!("Column '{}' not found", column))
+        })
+        .collect()
+}
+
+// Under `--strict`, every value listed in an `in` / `not in` filter has to
+// occur in the column. Unknown values are reported with the closest existing
+// ones, which is usually enough to spot a typo in a long allow-list.
+fn check_value_set_exists(
+    rows: &[Vec<String>],
+    filter: &Filter,
+    header_map: &HashMap<String, usize>,
+) -> Result<(), String> {
+    if filter.value_set.is_none() || filter.operator == "is" {
+        return Ok(());
+    }
+    let column_indices = filter_column_indices(filter, header_map)?;
+
+    let mut existing: Vec<&str> = Vec::new();
+    let mut seen = HashSet::new();
+    for row in rows {
+        for &i in &column_indices {
+            if let Some(cell) = row.get(i)
+                && seen.insert(cell.to_lowercase())
+            {
+                existing.push(cell);
+            }
+        }
+    }
+
+    for value in parse_value_list(&filter.value) {
+        if seen.contains(&value.to_lowercase()) {
+            continue;
+        }
+        let suggestions = closest_matches(&value, &existing, 3);
+        let mut message = format!(
+            "Value '{}' not found in column '{}'",
+            value,
+            filter.columns.join(", ")
+        );
+        if !suggestions.is_empty() {
+            message.push_str(&format!("; did you mean: {}?", suggestions.join(", ")));
+        }
+        return Err(message);
+    }
+
+    Ok(())
+}
+
+// Returns up to `count` candidates ordered by edit distance to `target`,
+// ignoring case.
+fn closest_matches(target: &str, candidates: &[&str], count: usize) -> Vec<String> {
+    let target = target.to_lowercase();
+    let mut scored: Vec<(usize, &str)> = candidates
+        .iter()
+        .map(|c| (strsim::levenshtein(&target, &c.to_lowercase()), *c))
+        .collect();
+    scored.sort_by_key(|(distance, _)| *distance);
+
+    scored
+        .into_iter()
+        .take(count)
+        .map(|(_, c)| c.to_string())
+        .collect()
+}
+
+fn check_condition(cell_value: &str, filter: &Filter) -> bool {
+    let filter_value = filter.value.as_str();
+
+    let derived_value;
+    let (cell_value, cast) = match filter.cast.as_deref() {
+        Some("magnitude") => match magnitude(cell_value, filter.decimal_comma) {
+            Some(m) => {
+                derived_value = m.to_string();
+                (derived_value.as_str(), Some("num"))
+            }
+            None => return false,
+        },
+        // Only whole numbers have a remainder; `4.0` counts, `4.5` doesn't.
+        Some("mod") => match (
+            parse_number(cell_value, filter.decimal_comma),
+            filter.modulus,
+        ) {
+            (Some(n), Some(divisor)) if n.is_finite() && n.fract() == 0.0 => {
+                derived_value = (n as i64).rem_euclid(divisor).to_string();
+                (derived_value.as_str(), Some("num"))
+            }
+            _ => return false,
+        },
+        cast => (cell_value, cast),
+    };
+
+    if let Some(cast) = cast
+        && comparison_holds(&filter.operator, Ordering::Equal).is_some()
+    {
+        return compare_as(cell_value, filter_value, cast, filter.decimal_comma)
+            .and_then(|ordering| comparison_holds(&filter.operator, ordering))
+            .unwrap_or(false);
+    }
+
+    match filter.operator.as_str() {
+        "equals" | "=" | "==" => cells_equal(cell_value, filter_value),
+        "not equals" | "!=" => !cells_equal(cell_value, filter_value),
+        "greater" | ">" | "less" | "<" | ">=" | "<=" => {
+            compare_ordered(cell_value, filter_value, filter.decimal_comma)
+                .and_then(|ordering| comparison_holds(&filter.operator, ordering))
+                .unwrap_or(false)
+        }
+        "contains" => cell_value
+            .to_lowercase()
+            .contains(&filter_value.to_lowercase()),
+        "starts" => cell_value
+            .to_lowercase()
+            .starts_with(&filter_value.to_lowercase()),
+        "ends" => cell_value
+            .to_lowercase()
+            .ends_with(&filter_value.to_lowercase()),
+        // Text cells are outside the distribution, so neither matches.
+        "above" | "below" => match (
+            parse_number(cell_value, filter.decimal_comma),
+            filter_value.parse::<f64>(),
+        ) {
+            (Some(n), Ok(threshold)) if filter.operator == "above" => n > threshold,
+            (Some(n), Ok(threshold)) => n < threshold,
+            _ => false,
+        },
+        "matches" => filter
+            .regex
+            .as_ref()
+            .is_some_and(|regex| regex.is_match(cell_value)),
+        // Cells that aren't dates fall outside any date range.
+        "since" => {
+            matches!((parse_date(cell_value), parse_date(filter_value)), (Some(a), Some(b)) if a >= b)
+        }
+        "until" => {
+            matches!((parse_date(cell_value), parse_date(filter_value)), (Some(a), Some(b)) if a < b)
+        }
+        "in" | "not in" => {
+            let is_member = filter
+                .value_set
+                .as_ref()
+                .is_some_and(|set| set.contains(&cell_value.to_lowercase()));
+            is_member == (filter.operator == "in")
+        }
+        // Cells that can't be compared with the bounds, e.g. text or empty
+        // cells, are outside the range: `not between` keeps them.
+        "between" | "not between" => {
+            let inside = filter
+                .range
+                .as_ref()
+                .is_some_and(|(low, high)| in_range(cell_value, low, high, filter.decimal_comma));
+            inside == (filter.operator == "between")
+        }
+        // By value: `5` and `5.0` are integers, `5.5` is a float. Cells that
+        // aren't numbers are neither.
+        "is" if filter_value == "integer" || filter_value == "float" => {
+            parse_number(cell_value, filter.decimal_comma)
+                .filter(|n| n.is_finite())
+                .is_some_and(|n| (n.fract() == 0.0) == (filter_value == "integer"))
+        }
+        // Empty cells are missing rather than malformed, so neither matches.
+        // `NaN` and `inf` parse as floats but don't count as numbers.
+        "is" if filter_value == "numeric" || filter_value == "not numeric" => {
+            let numeric =
+                parse_number(cell_value, filter.decimal_comma).is_some_and(|n| n.is_finite());
+            !cell_value.trim().is_empty() && numeric == (filter_value == "numeric")
+        }
+        // A cell in neither the true nor the false set never matches.
+        "is" => filter
+            .value_set
+            .as_ref()
+            .is_some_and(|set| set.contains(&cell_value.trim().to_lowercase())),
+        _ => false,
+    }
+}
+
+// Whether a cell comparing as `ordering` against the filter value passes the
+// comparison `operator`. `None` if it isn't a comparison.
+fn comparison_holds(operator: &str, ordering: Ordering) -> Option<bool> {
+    Some(match operator {
+        "equals" | "=" | "==" => ordering == Ordering::Equal,
+        "not equals" | "!=" => ordering != Ordering::Equal,
+        "greater" | ">" => ordering == Ordering::Greater,
+        "less" | "<" => ordering == Ordering::Less,
+        ">=" => ordering != Ordering::Less,
+        "<=" => ordering != Ordering::Greater,
+        _ => return None,
+    })
+}
+
+// Dates compare as dates, anything else ignores case.
+fn cells_equal(cell_value: &str, filter_value: &str) -> bool {
+    match (parse_date(cell_value), parse_date(filter_value)) {
+        (Some(a), Some(b)) => a == b,
+        _ => cell_value.to_lowercase() == filter_value.to_lowercase(),
+    }
+}
+
+// Numbers compare as numbers, then dates as dates, and anything else as text.
+// A date never compares with a non-date, so `date > 2024-01-01` skips `tbd`.
+fn compare_ordered(cell_value: &str, filter_value: &str, decimal_comma: bool) -> Option<Ordering> {
+    match (
+        parse_number(cell_value, decimal_comma),
+        parse_number(filter_value, decimal_comma),
+    ) {
+        (Some(a), Some(b)) => a.partial_cmp(&b),
+        _ => match (parse_date(cell_value), parse_date(filter_value)) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            (None, None) => Some(cell_value.cmp(filter_value)),
+            _ => None,
+        },
+    }
+}
+
+// Appends the running total of the `cumsum` column. Empty and non-numeric
+// cells add nothing; non-numeric ones are counted in a warning.
+fn apply_cumsum(
+    mut header_names: Vec<String>,
+    mut rows: Vec<Vec<String>>,
+    cumsum: &CumulativeSum,
+    query: &Query,
+    header_map: &HashMap<String, usize>,
+) -> Result<Table, String> {
+    let index = *header_map
+        .get(&cumsum.column)
+        .ok_or_else(|| format!("Column '{}' not found", cumsum.column))?;
+    if header_map.contains_key(&cumsum.into) {
+        return Err(format!("Column '{}' already exists", cumsum.into));
+    }
+
+    let mut total = 0.0;
+    let mut skipped = 0;
+    for row in rows.iter_mut() {
+        let cell = row.get(index).map(|c| c.trim()).unwrap_or("");
+        match parse_number(cell, query.decimal_comma) {
+            Some(n) => total += n,
+            None if cell.is_empty() => {}
+            None => skipped += 1,
+        }
+        row.resize(header_names.len(), String::new());
+        row.push(total.to_string());
+    }
+    header_names.push(cumsum.into.clone());
+
+    if skipped > 0 {
+        query.notices.warn(format!(
+            "counted {} non-numeric values in '{}' as 0",
+            skipped, cumsum.column
+        ));
+    }
+    Ok((header_names, rows))
+}
+
+// Keeps one row per key: the one with the greatest order value. On a tie the
+// row seen last wins. Keys stay in order of first appearance.
+fn apply_latest_per(
+    rows: Vec<Vec<String>>,
+    latest_per: &LatestPer,
+    header_map: &HashMap<String, usize>,
+) -> Result<Vec<Vec<String>>, String> {
+    let key_index = *header_map
+        .get(&latest_per.key_column)
+        .ok_or_else(|| format!("Column '{}' not found", latest_per.key_column))?;
+    let order_index = *header_map
+        .get(&latest_per.order_column)
+        .ok_or_else(|| format!("Column '{}' not found", latest_per.order_column))?;
+
+    let mut keys: Vec<String> = Vec::new();
+    let mut latest: HashMap<String, Vec<String>> = HashMap::new();
+    for row in rows {
+        let key = row.get(key_index).cloned().unwrap_or_default();
+        match latest.get(&key) {
+            Some(current) => {
+                let empty = String::new();
+                let current_value = current.get(order_index).unwrap_or(&empty);
+                let value = row.get(order_index).unwrap_or(&empty);
+                if compare_timestamps(value, current_value) != Ordering::Less {
+                    latest.insert(key, row);
+                }
+            }
+            None => {
+                keys.push(key.clone());
+                latest.insert(key, row);
+            }
+        }
+    }
+
+    Ok(keys
+        .into_iter()
+        .filter_map(|key| latest.remove(&key))
+        .collect())
+}
+
+// Orders numbers numerically and timestamps chronologically, falling back to
+// plain string order.
+fn compare_timestamps(a: &str, b: &str) -> Ordering {
+    if let (Ok(a), Ok(b)) = (a.parse::<f64>(), b.parse::<f64>()) {
+        return a.partial_cmp(&b).unwrap_or(Ordering::Equal);
+    }
+    match (parse_datetime(a), parse_datetime(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+// Number of digits before the decimal point, i.e. floor(log10(|value|)) + 1:
+// 3 for 100 through 999.99. Values below 1 get 0 or less (0.05 is -1), and
+// zero and non-numbers have none.
+fn magnitude(cell_value: &str, decimal_comma: bool) -> Option<i32> {
+    let value = parse_number(cell_value, decimal_comma)?.abs();
+    (value.is_finite() && value > 0.0).then(|| value.log10().floor() as i32 + 1)
+}
+
+// Inclusive on both ends. Compares as numbers when the bounds are numbers,
+// otherwise as dates.
+fn in_range(cell_value: &str, low: &str, high: &str, decimal_comma: bool) -> bool {
+    match (
+        parse_number(low, decimal_comma),
+        parse_number(high, decimal_comma),
+    ) {
+        (Some(low), Some(high)) => {
+            parse_number(cell_value, decimal_comma).is_some_and(|n| low <= n && n <= high)
+        }
+        _ => match (parse_date(cell_value), parse_date(low), parse_date(high)) {
+            (Some(date), Some(low), Some(high)) => low <= date && date <= high,
+            _ => false,
+        },
+    }
+}
+
+// Compares strictly as the given type. Cells that can't be read as that type
+// don't compare at all, so they never match. Text compares ignoring case.
+fn compare_as(
+    cell_value: &str,
+    filter_value: &str,
+    cast: &str,
+    decimal_comma: bool,
+) -> Option<Ordering> {
+    match cast.to_lowercase().as_str() {
+        "num" => {
+            let a = parse_number(cell_value, decimal_comma)?;
+            let b = parse_number(filter_value, decimal_comma)?;
+            a.partial_cmp(&b)
+        }
+        "date" => Some(parse_date(cell_value)?.cmp(&parse_date(filter_value)?)),
+        _ => Some(cell_value.to_lowercase().cmp(&filter_value.to_lowercase())),
+    }
+}
+
+// Earliest and latest date in the column. Both come out as plain dates
+// unless one of the values carries a time of day. Cells that aren't dates are
+// skipped with a warning; empty cells are skipped silently.
+fn date_range(
+    rows: &[Vec<String>],
+    column: &str,
+    header_map: &HashMap<String, usize>,
+    notices: &Notices,
+) -> Result<Vec<String>, String> {
+    let index = *header_map
+        .get(column)
+        .ok_or_else(|| format!("Column '{}' not found", column))?;
+
+    let mut range: Option<(NaiveDateTime, NaiveDateTime)> = None;
+    let mut skipped = 0;
+    for row in rows {
+        let cell = row.get(index).map(String::as_str).unwrap_or("");
+        if cell.trim().is_empty() {
+            continue;
+        }
+        let Some(value) = parse_datetime(cell) else {
+            skipped += 1;
+            continue;
+        };
+        range = Some(match range {
+            Some((min, max)) => (min.min(value), max.max(value)),
+            None => (value, value),
+        });
+    }
+
+    if skipped > 0 {
+        notices.warn(format!(
+            "skipped {} non-date values in '{}'",
+            skipped, column
+        ));
+    }
+
+    let Some((min, max)) = range else {
+        return Ok(vec![String::new(), String::new()]);
+    };
+    let midnight = chrono::NaiveTime::MIN;
+    let format = if min.time() == midnight && max.time() == midnight {
+        "%Y-%m-%d"
+    } else {
+        "%Y-%m-%d %H:%M:%S"
+    };
+
+    Ok(vec![
+        min.format(format).to_string(),
+        max.format(format).to_string(),
+    ])
+}
+
+// Buckets rows by the group column in order of first appearance and reduces
+// each bucket with the query's aggregate, or in the `group_sort` order. Without
+// an aggregate this is just the distinct group values, and the value sorts
+// order by key. Empty keys form a bucket of their own.
+fn apply_group(
+    rows: Vec<Vec<String>>,
+    group_column: &str,
+    query: &Query,
+    header_map: &HashMap<String, usize>,
+) -> Result<Table, String> {
+    let group_index = *header_map
+        .get(group_column)
+        .ok_or_else(|| format!("Column '{}' not found", group_column))?;
+
+    let mut keys: Vec<String> = Vec::new();
+    let mut buckets: HashMap<String, Vec<Vec<String>>> = HashMap::new();
+    for row in rows {
+        let mut key = row.get(group_index).cloned().unwrap_or_default();
+        if let Some(width) = query.group_bucket_width {
+            key = bucket_key(&key, width, query.decimal_comma);
+        }
+        if !buckets.contains_key(&key) {
+            keys.push(key.clone());
+        }
+        buckets.entry(key).or_default().push(row);
+    }
+
+    let key_label = if query.group_bucket_width.is_some() {
+        format!("{}_bucket", group_column)
+    } else {
+        group_column.to_string()
+    };
+
+    let Some(aggregate) = &query.aggregate else {
+        match query.group_sort.as_deref() {
+            Some("count") => keys.sort_by_key(|key| std::cmp::Reverse(buckets[key].len())),
+            Some(_) => keys.sort_by(|a, b| compare_cells(a, b, query.decimal_comma)),
+            None => {}
+        }
+        return Ok((
+            vec![key_label],
+            keys.into_iter().map(|key| vec![key]).collect(),
+        ));
+    };
+
+    let mut grouped_rows = Vec::new();
+    for key in keys {
+        let value = compute_aggregate(aggregate, &buckets[&key], query, header_map)?;
+        grouped_rows.push(vec![key, value]);
+    }
+
+    // Sorts are stable, so ties keep the order of first appearance.
+    match query.group_sort.as_deref() {
+        Some("key") => {
+            grouped_rows.sort_by(|a, b| compare_cells(&a[0], &b[0], query.decimal_comma))
+        }
+        Some("count") => grouped_rows.sort_by_key(|row| std::cmp::Reverse(buckets[&row[0]].len())),
+        Some("value-asc") => {
+            grouped_rows.sort_by(|a, b| compare_cells(&a[1], &b[1], query.decimal_comma))
+        }
+        Some("value-desc") => {
+            grouped_rows.sort_by(|a, b| compare_cells(&b[1], &a[1], query.decimal_comma))
+        }
+        _ => {}
+    }
+
+    Ok((vec![key_label, aggregate_label(aggregate)], grouped_rows))
+}
+
+// The lower bound of the `width`-sized range the value falls into, e.g. 100
+// for 150 with a width of 100. Non-numeric values share an "invalid" bucket.
+// Bounds are written with as many decimals as the width, so a width of 0.1
+// gives `0.3` rather than `0.30000000000000004`.
+fn bucket_key(value: &str, width: f64, decimal_comma: bool) -> String {
+    let Some(number) = parse_number(value, decimal_comma).filter(|n| n.is_finite()) else {
+        return "invalid".to_string();
+    };
+    // 0.3 / 0.1 is 2.9999999999999996; values on a bound belong to it.
+    let steps = number / width;
+    let steps = if (steps - steps.round()).abs() < 1e-9 {
+        steps.round()
+    } else {
+        steps.floor()
+    };
+    let decimals = width
+        .to_string()
+        .split_once('.')
+        .map_or(0, |(_, fraction)| fraction.len());
+    // Adding 0.0 turns -0 into 0.
+    format!("{:.*}", decimals, steps * width + 0.0)
+}
+
+// Aggregate results are labelled with the column they were computed over, so
+// `sort <column>` can refer to them afterwards.
+fn aggregate_label(aggregate: &Aggregate) -> String {
+    aggregate
+        .column
+        .clone()
+        .unwrap_or_else(|| aggregate.function.clone())
+}
+
+fn compute_aggregate(
+    aggregate: &Aggregate,
+    rows: &[Vec<String>],
+    query: &Query,
+    header_map: &HashMap<String, usize>,
+) -> Result<String, String> {
+    let mut values: Vec<&str> = match &aggregate.column {
+        Some(column) => {
+            let index = *header_map
+                .get(column)
+                .ok_or_else(|| format!("Column '{}' not found", column))?;
+            rows.iter()
+                .map(|row| row.get(index).map(String::as_str).unwrap_or(""))
+                .collect()
+        }
+        None => Vec::new(),
+    };
+
+    // Empty cells are dealt with once here, so the functions below only see
+    // the values they should aggregate. `--agg-nulls` is about numbers: the
+    // other functions always skip empty cells.
+    let is_empty = |v: &&str| v.trim().is_empty();
+    let numeric = matches!(aggregate.function.as_str(), "sum" | "avg" | "min" | "max");
+    match if numeric {
+        query.agg_nulls.as_str()
+    } else {
+        "skip"
+    } {
+        "zero" => {
+            for value in values.iter_mut().filter(|v| is_empty(v)) {
+                *value = "0";
+            }
+        }
+        "error" => {
+            if values.iter().any(is_empty) {
+                return Err(format!(
+                    "Empty value in column '{}' (--agg-nulls error)",
+                    aggregate.column.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+        _ => values.retain(|v| !is_empty(v)),
+    }
+
+    match aggregate.function.as_str() {
+        // Without a column every row counts; with one every remaining value.
+        "count" => match aggregate.column {
+            Some(_) => Ok(values.len().to_string()),
+            None => Ok(rows.len().to_string()),
+        },
+        "group-concat" => Ok(values.join(&query.concat_separator)),
+        // In input order; all-empty groups give an empty cell.
+        "first-nonempty" => Ok(values
+            .iter()
+            .find(|v| !is_empty(v))
+            .map(|v| v.to_string())
+            .unwrap_or_default()),
+        "last-nonempty" => Ok(values
+            .iter()
+            .rfind(|v| !is_empty(v))
+            .map(|v| v.to_string())
+            .unwrap_or_default()),
+        "all" => Ok(values
+            .iter()
+            .all(|v| parse_bool(v) == Some(true))
+            .to_string()),
+        "any" => Ok(values
+            .iter()
+            .any(|v| parse_bool(v) == Some(true))
+            .to_string()),
+        "sum" | "avg" | "min" | "max" => {
+            let numbers: Vec<f64> = values
+                .iter()
+                .filter_map(|v| parse_number(v, query.decimal_comma))
+                .collect();
+            let skipped = values.len() - numbers.len();
+            if skipped > 0 {
+                query.notices.warn(format!(
+                    "skipped {} non-numeric values in '{}'",
+                    skipped,
+                    aggregate.column.as_deref().unwrap_or_default()
+                ));
+            }
+            Ok(numeric_aggregate(&aggregate.function, &numbers))
+        }
+        _ => Err(format!("Unknown aggregate: {}", aggregate.function)),
+    }
+}
+
+// The sum of no values is 0; their average, minimum and maximum are empty.
+fn numeric_aggregate(function: &str, numbers: &[f64]) -> String {
+    let sum: f64 = numbers.iter().sum();
+    let result = match function {
+        "sum" => Some(sum),
+        "avg" => (!numbers.is_empty()).then(|| sum / numbers.len() as f64),
+        "min" => numbers.iter().copied().reduce(f64::min),
+        _ => numbers.iter().copied().reduce(f64::max),
+    };
+    result.map(|n| n.to_string()).unwrap_or_default()
+}
+
+// Reads the usual spellings of a boolean cell. Anything else is neither true
+// nor false.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "t" | "yes" | "y" | "1" => Some(true),
+        "false" | "f" | "no" | "n" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn apply_sort(
+    rows: &mut [Vec<String>],
+    sort_keys: &[(String, bool)],
+    precision: Option<u32>,
+    decimal_comma: bool,
+    header_map: &HashMap<String, usize>,
+) -> Result<(), String> {
+    let keys = sort_keys
+        .iter()
+        .map(|(column, descending)| {
+            header_map
+                .get(column)
+                .map(|&index| (index, *descending))
+                .ok_or_else(|| format!("Column '{}' not found", column))
+        })
+        .collect::<Result<Vec<(usize, bool)>, String>>()?;
+
+    let empty_string = String::new();
+
+    rows.sort_by(|a, b| {
+        keys.iter()
+            .map(|&(column_index, descending)| {
+                let val_a = a.get(column_index).unwrap_or(&empty_string);
+                let val_b = b.get(column_index).unwrap_or(&empty_string);
+
+                let rounded = precision.and_then(|decimals| {
+                    let scale = 10f64.powi(decimals as i32);
+                    let num_a = parse_number(val_a, decimal_comma)?;
+                    let num_b = parse_number(val_b, decimal_comma)?;
+                    (num_a * scale)
+                        .round()
+                        .partial_cmp(&(num_b * scale).round())
+                });
+                let comparison =
+                    rounded.unwrap_or_else(|| compare_cells(val_a, val_b, decimal_comma));
+
+                if descending {
+                    comparison.reverse()
+                } else {
+                    comparison
+                }
+            })
+            .find(|comparison| comparison.is_ne())
+            .unwrap_or(Ordering::Equal)
+    });
+
+    Ok(())
+}
+
+// Reads a number. With `decimal_comma` (--locale eu) `3,14` and `1.234,5` are
+// read as 3.14 and 1234.5 too.
+fn parse_number(value: &str, decimal_comma: bool) -> Option<f64> {
+    let value = value.trim();
+    if decimal_comma && value.contains(',') {
+        return value.replace('.', "").replace(',', ".").parse().ok();
+    }
+    value.parse().ok()
+}
+
+// Numbers compare by value, dates chronologically, anything else as text.
+fn compare_cells(a: &str, b: &str, decimal_comma: bool) -> Ordering {
+    match (
+        parse_number(a, decimal_comma),
+        parse_number(b, decimal_comma),
+    ) {
+        (Some(num_a), Some(num_b)) => num_a.partial_cmp(&num_b).unwrap_or(Ordering::Equal),
+        _ => match (parse_date(a), parse_date(b)) {
+            (Some(date_a), Some(date_b)) => date_a.cmp(&date_b),
+            // A mixed column keeps each kind together: empty cells, then
+            // numbers, dates and other text.
+            _ => cell_kind(a, decimal_comma)
+                .cmp(&cell_kind(b, decimal_comma))
+                .then_with(|| a.cmp(b)),
+        },
+    }
+}
+
+fn cell_kind(value: &str, decimal_comma: bool) -> u8 {
+    if value.trim().is_empty() {
+        0
+    } else if parse_number(value, decimal_comma).is_some() {
+        1
+    } else if parse_date(value).is_some() {
+        2
+    } else {
+        3
+    }
+}
+
+// Keeps the first row for each combination of values in `columns` (all of the
+// row without them), in order of first appearance.
+fn apply_distinct(
+    rows: Vec<Vec<String>>,
+    columns: Option<&[String]>,
+    column_defaults: &HashMap<String, String>,
+    header_map: &HashMap<String, usize>,
+) -> Result<Vec<Vec<String>>, String> {
+    // A missing column with a default is the same in every row, so it is
+    // left out of the key.
+    let column_indices = columns
+        .map(|columns| {
+            columns
+                .iter()
+                .filter(|col| header_map.contains_key(*col) || !column_defaults.contains_key(*col))
+                .map(|col| {
+                    header_map
+                        .get(col)
+                        .copied()
+                        .ok_or_else(|| format!("Column '{}' not found", col))
+                })
+                .collect::<Result<Vec<usize>, String>>()
+        })
+        .transpose()?;
+
+    let mut seen: HashSet<Vec<String>> = HashSet::new();
+    Ok(rows
+        .into_iter()
+        .filter(|row| {
+            let key = match &column_indices {
+                Some(indices) => indices
+                    .iter()
+                    .map(|&i| row.get(i).cloned().unwrap_or_default())
+                    .collect(),
+                None => row.clone(),
+            };
+            seen.insert(key)
+        })
+        .collect())
+}
+
+fn select_columns(
+    rows: Vec<Vec<String>>,
+    columns: &[String],
+    column_defaults: &HashMap<String, String>,
+    header_map: &HashMap<String, usize>,
+) -> Result<Vec<Vec<String>>, String> {
+    // `None` is a missing column that has a default.
+    let mut column_indices = Vec::new();
+    for col in columns {
+        let index = header_map.get(col).copied();
+        if index.is_none() && !column_defaults.contains_key(col) {
+            return Err(format!("Column '{}' not found", col));
+        }
+        column_indices.push((index, column_defaults.get(col)));
+    }
+
+    let mut result = Vec::new();
+
+    result.push(columns.to_vec());
+    for row in rows {
+        let selected_row: Vec<String> = column_indices
+            .iter()
+            .map(|&(index, default)| {
+                let cell = index.and_then(|i| row.get(i)).map_or("", |c| c.as_str());
+                match default {
+                    Some(default) if cell.is_empty() => default.clone(),
+                    _ => cell.to_string(),
+                }
+            })
+            .collect();
+        result.push(selected_row);
+    }
+
+    Ok(result)
+}
+
+pub enum InferredValue<'a> {
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Text(&'a str),
+}
+
+// Types a cell for structured output. Only values that are clearly numbers
+// become numbers: anything that wouldn't survive a round trip, like `007`,
+// `+5` or `1.`, stays text.
+pub fn infer_value(cell: &str) -> InferredValue<'_> {
+    match cell {
+        "true" => return InferredValue::Bool(true),
+        "false" => return InferredValue::Bool(false),
+        _ => {}
+    }
+
+    let digits = cell.strip_prefix('-').unwrap_or(cell);
+    let (whole, fraction) = match digits.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (digits, None),
+    };
+    let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let canonical_whole = is_digits(whole) && (whole == "0" || !whole.starts_with('0'));
+
+    match fraction {
+        None if canonical_whole => cell
+            .parse()
+            .map(InferredValue::Integer)
+            .unwrap_or(InferredValue::Text(cell)),
+        Some(fraction) if canonical_whole && is_digits(fraction) => cell
+            .parse()
+            .ok()
+            .filter(|n: &f64| n.is_finite())
+            .map(InferredValue::Float)
+            .unwrap_or(InferredValue::Text(cell)),
+        _ => InferredValue::Text(cell),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Query {
+        parse_query(input, None, false, &HashMap::new()).unwrap()
+    }
+
+    fn table(csv: &str) -> Table {
+        read_csv(csv.as_bytes(), b',').unwrap()
+    }
+
+    fn run(input: &str, csv: &str) -> Vec<Vec<String>> {
+        run_query(input, csv.as_bytes()).unwrap()
+    }
+
+    // The cells of one column, header excluded.
+    fn column(results: &[Vec<String>], index: usize) -> Vec<&str> {
+        results[1..].iter().map(|row| row[index].as_str()).collect()
+    }
+
+    // A fresh, empty directory for one test's files.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("flexiql-lib-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_simple_query() {
+        let query = parse("employees >> show name");
+        assert_eq!(query.table_name, "employees.csv");
+        assert!(query.columns.is_some());
+    }
+
+    #[test]
+    fn test_parse_filter_query() {
+        let query = parse("employees >> salary greater than 50000 >> show name, salary");
+        assert_eq!(query.filters.len(), 1);
+        assert!(query.columns.is_some());
+    }
+
+    #[test]
+    fn test_trailing_comment() {
+        let query = parse("employees >> name equals A#1 >> show name # weekly report");
+        assert_eq!(query.filters[0].value, "A#1");
+        assert_eq!(query.columns, Some(vec!["name".to_string()]));
+
+        let query = parse("employees >> name equals O'Brien # note");
+        assert_eq!(query.filters[0].value, "O'Brien");
+        let query = parse(r#"employees >> name equals "A # B" # note"#);
+        assert_eq!(query.filters[0].value, "A # B");
+    }
+
+    #[test]
+    fn test_relative_date_filter() {
+        let today = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+        let filter = parse_filter_at("date greater than today-7d", today)
+            .unwrap()
+            .unwrap();
+        assert_eq!(filter.value, "2024-03-03");
+
+        let (header_names, rows) = table("date\n2024-03-01\n2024-03-03\n2024-03-04\n2024-03-10\n");
+        let rows = apply_filters(rows, &[filter], &build_header_map(&header_names)).unwrap();
+        assert_eq!(rows, vec![vec!["2024-03-04"], vec!["2024-03-10"]]);
+
+        let filter = parse_filter_at("date equals yesterday", today)
+            .unwrap()
+            .unwrap();
+        assert_eq!(filter.value, "2024-03-09");
+
+        for value in ["todayé", "today-1é", "today+é", "today-99999999999999w"] {
+            let filter = parse_filter_at(&format!("name equals {}", value), today)
+                .unwrap()
+                .unwrap();
+            assert_eq!(filter.value, value);
+        }
+    }
+
+    #[test]
+    fn test_date_prefixed_values() {
+        let csv = "id\n2024-01-05-A\n2024-01-05-B\n2024-01-059999\n2024-01-05T10:00:00\n";
+        let results = run("t >> id equals 2024-01-05-A", csv);
+        assert_eq!(column(&results, 0), vec!["2024-01-05-A"]);
+        let results = run("t >> id != 2024-01-05-A", csv);
+        assert_eq!(
+            column(&results, 0),
+            vec!["2024-01-05-B", "2024-01-059999", "2024-01-05T10:00:00"]
+        );
+
+        assert_eq!(parse_date("2024-01-05 10:00"), parse_date("2024-01-05"));
+        assert_eq!(
+            parse_date("2024-01-05T10:00:00.5Z"),
+            parse_date("2024-01-05")
+        );
+        assert_eq!(parse_date("2024-01-05-A"), None);
+    }
+
+    #[test]
+    fn test_large_in_set() {
+        let values: Vec<String> = (0..10_000).map(|i| format!("Code{}", i * 2)).collect();
+        let query = parse(&format!("t >> code in {}", values.join(", ")));
+        assert_eq!(query.filters[0].value_set.as_ref().unwrap().len(), 10_000);
+
+        let csv: String = std::iter::once("code".to_string())
+            .chain((0..20_000).map(|i| format!("CODE{}", i)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let (header_names, rows) = table(&csv);
+        let rows = apply_filters(rows, &query.filters, &build_header_map(&header_names)).unwrap();
+        assert_eq!(rows.len(), 10_000);
+        assert!(
+            rows.iter()
+                .all(|row| row[0][4..].parse::<u32>().unwrap() % 2 == 0)
+        );
+    }
+
+    #[test]
+    fn test_group_concat() {
+        let csv = "customer,product\nann,tea\nbob,jam\nann,cake\n";
+        assert_eq!(
+            run("orders >> group customer group-concat product", csv),
+            vec![
+                vec!["customer", "product"],
+                vec!["ann", "tea, cake"],
+                vec!["bob", "jam"],
+            ]
+        );
+
+        let mut query = parse("orders >> group customer group-concat product");
+        let (header_names, rows) = table(csv);
+        query.concat_separator = "|".to_string();
+        let results = execute_query_on(&query, header_names, rows).unwrap();
+        assert_eq!(results[1], vec!["ann", "tea|cake"]);
+    }
+
+    #[test]
+    fn test_strict_in_suggestions() {
+        let mut query = parse("t >> dept in sales, enginering");
+        query.strict = true;
+        let (header_names, rows) = table("dept\nSales\nEngineering\nEngineers\nLegal\nSupport\n");
+        let error = execute_query_on(&query, header_names, rows).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Value 'enginering' not found in column 'dept'; did you mean: Engineering, Engineers, Sales?"
+        );
+    }
+
+    #[test]
+    fn test_all_any() {
+        let all_true = "check,passed\na,yes\nb,true\nc,1\n";
+        assert_eq!(run("checks >> all passed", all_true)[1], vec!["true"]);
+        assert_eq!(run("checks >> any passed", all_true)[1], vec!["true"]);
+
+        let mixed = "check,passed\na,yes\nb,no\nc,1\n";
+        assert_eq!(run("checks >> all passed", mixed)[1], vec!["false"]);
+        assert_eq!(run("checks >> any passed", mixed)[1], vec!["true"]);
+        assert_eq!(
+            run("checks >> any passed", "check,passed\na,no\n")[1],
+            vec!["false"]
+        );
+
+        // A column named `any` can still be filtered on.
+        let results = run("t >> any equals 3 >> show id", "id,any\n1,3\n2,4\n");
+        assert_eq!(column(&results, 0), vec!["1"]);
+    }
+
+    #[test]
+    fn test_filter_on_column_list() {
+        let csv = "id,first_name,last_name\n1,Ann,Lee\n2,Bob,Johnson\n3,John,Smith\n4,Eve,Stone\n";
+        let results = run("people >> where (first_name, last_name) contains john", csv);
+        assert_eq!(column(&results, 0), vec!["2", "3"]);
+    }
+
+    #[test]
+    fn test_strict_parse_typos() {
+        let strict = |input: &str| parse_query(input, None, true, &HashMap::new());
+        assert_eq!(
+            strict("employees >> shwo name").unwrap_err().to_string(),
+            "Unknown stage 'shwo'; did you mean 'show'?"
+        );
+        assert!(strict("employees >> colums").is_err());
+        for column in ["country", "cash", "num", "tax", "port", "size"] {
+            let query = strict(&format!("t >> {} equals US", column)).unwrap();
+            assert_eq!(query.filters[0].columns, vec![column]);
+        }
+        assert!(strict("t >> size > 3").is_ok());
+    }
+
+    #[test]
+    fn test_since_until() {
+        let csv = "id,timestamp\n1,2023-12-31T23:59:00\n2,2024-01-01\n3,2024-01-31 18:00\n4,2024-02-01\n5,2024-02-02\n";
+        let results = run(
+            "logs >> since 2024-01-01 on timestamp >> until 2024-02-01 on timestamp",
+            csv,
+        );
+        assert_eq!(column(&results, 0), vec!["2", "3"]);
+    }
+
+    #[test]
+    fn test_bucket_group_key() {
+        let csv = "amount\n5\n99.5\n100\n250\n-20\nn/a\n199\n";
+        let mut results = run("data >> group bucket(amount, 100) count", csv);
+        results[1..].sort();
+        assert_eq!(
+            results[1..],
+            [
+                vec!["-100", "1"],
+                vec!["0", "2"],
+                vec!["100", "2"],
+                vec!["200", "1"],
+                vec!["invalid", "1"],
+            ]
+        );
+
+        let csv = "x\n0.05\n0.1\n0.25\n0.3\n0.35\n-0.05\n";
+        let mut results = run("data >> group bucket(x, 0.1) count", csv);
+        results[1..].sort();
+        assert_eq!(
+            results[1..],
+            [
+                vec!["-0.1", "1"],
+                vec!["0.0", "1"],
+                vec!["0.1", "1"],
+                vec!["0.2", "1"],
+                vec!["0.3", "2"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fail_if_empty() {
+        let mut query = parse("t >> id equals 9");
+        query.fail_if_empty = true;
+        let (header_names, rows) = table("id,name\n");
+        let error = execute_query_on(&query, header_names, rows).unwrap_err();
+        assert_eq!(error.to_string(), "Table 't.csv' has no data rows");
+
+        // Filtering everything away is not an empty table.
+        let (header_names, rows) = table("id,name\n1,Ann\n");
+        let results = execute_query_on(&query, header_names, rows).unwrap();
+        assert_eq!(results, vec![vec!["id", "name"]]);
+    }
+
+    #[test]
+    fn test_latest_per() {
+        let csv = "user,timestamp,action\n\
+                   ann,2024-01-02,login\n\
+                   bob,2024-01-01,login\n\
+                   ann,2024-01-05,logout\n\
+                   bob,2024-01-03,edit\n\
+                   ann,2024-01-05,save\n\
+                   ann,2024-01-04,edit\n";
+        let mut results = run("events >> latest-per user on timestamp", csv);
+        results[1..].sort();
+        assert_eq!(
+            results[1..],
+            [
+                vec!["ann", "2024-01-05", "save"],
+                vec!["bob", "2024-01-03", "edit"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inline_casts() {
+        let csv = "code\n0150\n99\n100\n2000\nabc\n";
+        let results = run("data >> where num(code) greater than 100", csv);
+        assert_eq!(column(&results, 0), vec!["0150", "2000"]);
+
+        // As text, `99` sorts after `100`.
+        let results = run("data >> where text(code) greater than 100", csv);
+        assert_eq!(column(&results, 0), vec!["99", "2000", "abc"]);
+    }
+
+    #[test]
+    fn test_composite_key_join() {
+        let dir = temp_dir("join");
+        std::fs::write(
+            dir.join("shipments.csv"),
+            "order_id,line_no,carrier\n1,1,ups\n1,2,dhl\n2,1,fedex\n",
+        )
+        .unwrap();
+        let csv = "order_id,line_no,item\n1,2,lamp\n2,1,desk\n2,2,chair\n";
+        let input = format!(
+            "orders >> join {} on (order_id, line_no)",
+            dir.join("shipments").display()
+        );
+        assert_eq!(
+            run(&input, csv),
+            vec![
+                vec!["order_id", "line_no", "item", "carrier"],
+                vec!["1", "2", "lamp", "dhl"],
+                vec!["2", "1", "desk", "fedex"],
+            ]
+        );
+
+        let error = parse_query(
+            "orders >> join shipments on (order_id, line_no) = (id)",
+            None,
+            false,
+            &HashMap::new(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().starts_with("Join keys don't line up"));
+    }
+
+    #[test]
+    fn test_nested_parentheses() {
+        let error =
+            parse_query("t >> ((((a)))) equals 1", None, false, &HashMap::new()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Nested parentheses aren't supported in a filter: ((((a)))) equals 1"
+        );
+        let deep = format!(
+            "t >> {}a{} equals 1",
+            "(".repeat(10_000),
+            ")".repeat(10_000)
+        );
+        assert!(parse_query(&deep, None, false, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_columns_stage() {
+        assert_eq!(
+            run("data >> columns", "id,name,city\n1,Ann,Oslo\n"),
+            vec![vec!["column"], vec!["id"], vec!["name"], vec!["city"]]
+        );
+    }
+
+    #[test]
+    fn test_minmax_dates() {
+        let csv = "timestamp\n2024-03-05\n2024/01/09\nsoon\n\n2024-12-01\n";
+        assert_eq!(
+            run("events >> minmax timestamp", csv),
+            vec![vec!["min", "max"], vec!["2024-01-09", "2024-12-01"]]
+        );
+
+        let csv = "timestamp\n2024-03-05T08:30:00\n2024-03-04\n";
+        assert_eq!(
+            run("events >> minmax timestamp", csv)[1],
+            vec!["2024-03-04 00:00:00", "2024-03-05 08:30:00"]
+        );
+    }
+
+    #[test]
+    fn test_sniff_delimiter() {
+        let path = temp_dir("sniff").join("people.txt");
+        std::fs::write(&path, "name\tcity\nLee, Ann\tOslo\nBob\tRome\n").unwrap();
+        let mut query = parse("t");
+        query.delimiter = None;
+        assert_eq!(
+            table_delimiter(path.to_str().unwrap(), &query).unwrap(),
+            b'\t'
+        );
+
+        assert_eq!(sniff_delimiter("a;b;c\n1;2,5;3\n"), b';');
+        assert_eq!(sniff_delimiter("a|b\n\"x|y\"|z\n"), b'|');
+    }
+
+    #[test]
+    fn test_cheap_filters_first() {
+        let query = parse("t >> name matches ^A and dept equals ops");
+        let (header_names, rows) = table("name,dept\nAnn,ops\nAmy,it\nBob,ops\nCal,it\n");
+        let header_map = build_header_map(&header_names);
+
+        let mut checked: Vec<String> = Vec::new();
+        let counted = filter_rows(
+            rows.clone(),
+            &query.filters,
+            &header_map,
+            |row, filter, columns| {
+                checked.push(filter.operator.clone());
+                row_matches(row, filter, columns)
+            },
+        )
+        .unwrap();
+
+        // The regex only runs on the two rows whose dept matched.
+        assert_eq!(checked.iter().filter(|op| *op == "equals").count(), 4);
+        assert_eq!(checked.iter().filter(|op| *op == "matches").count(), 2);
+        assert_eq!(checked[0], "equals");
+        assert_eq!(
+            counted,
+            apply_filters(rows, &query.filters, &header_map).unwrap()
+        );
+        assert_eq!(counted, vec![vec!["Ann", "ops"]]);
+    }
+
+    #[test]
+    fn test_agg_nulls() {
+        let csv = "user,amount,email\nann,10,\nann,,ann@x.org\nbob,2,bob@x.org\n";
+        let with_nulls = |input: &str, mode: &str| {
+            let mut query = parse(input);
+            query.agg_nulls = mode.to_string();
+            let (header_names, rows) = table(csv);
+            execute_query_on(&query, header_names, rows)
+        };
+
+        assert_eq!(with_nulls("t >> avg amount", "skip").unwrap()[1], vec!["6"]);
+        assert_eq!(with_nulls("t >> avg amount", "zero").unwrap()[1], vec!["4"]);
+        assert_eq!(with_nulls("t >> min amount", "zero").unwrap()[1], vec!["0"]);
+        assert_eq!(
+            with_nulls("t >> sum amount", "error")
+                .unwrap_err()
+                .to_string(),
+            "Empty value in column 'amount' (--agg-nulls error)"
+        );
+
+        // Only the numeric aggregates are affected.
+        for mode in ["skip", "zero", "error"] {
+            let results = with_nulls("t >> group user first-nonempty email", mode).unwrap();
+            assert_eq!(results[1], vec!["ann", "ann@x.org"]);
+            let results = with_nulls("t >> count email", mode).unwrap();
+            assert_eq!(results[1], vec!["2"]);
+        }
+    }
+
+    #[test]
+    fn test_split_column() {
+        let csv = "id,full_name\n1,Ann Lee\n2,Bob van Dyke\n3,Cher\n";
+        assert_eq!(
+            run(r#"data >> split full_name " " into first, last"#, csv),
+            vec![
+                vec!["id", "full_name", "first", "last"],
+                vec!["1", "Ann Lee", "Ann", "Lee"],
+                vec!["2", "Bob van Dyke", "Bob", "van"],
+                vec!["3", "Cher", "Cher", ""],
+            ]
+        );
+
+        let mut query = parse(r#"data >> split full_name " " into first, last"#);
+        query.split_remainder = true;
+        let (header_names, rows) = table(csv);
+        let results = execute_query_on(&query, header_names, rows).unwrap();
+        assert_eq!(results[2], vec!["2", "Bob van Dyke", "Bob", "van Dyke"]);
+    }
+
+    #[test]
+    fn test_merge_columns() {
+        let csv = "first,last,age\nAnn,Lee,30\nBob,,41\n";
+        assert_eq!(
+            run(r#"data >> merge first, last into name with " ""#, csv),
+            vec![
+                vec!["first", "last", "age", "name"],
+                vec!["Ann", "Lee", "30", "Ann Lee"],
+                vec!["Bob", "", "41", "Bob "],
+            ]
+        );
+
+        let mut query = parse(r#"data >> merge first, last INTO name WITH "-""#);
+        query.merge_drop = true;
+        let (header_names, rows) = table(csv);
+        let results = execute_query_on(&query, header_names, rows).unwrap();
+        assert_eq!(results[..2], [vec!["age", "name"], vec!["30", "Ann-Lee"]]);
+
+        // Lowercasing `İ` makes it longer, which used to throw the offsets off.
+        let merge = parse_column_merge("merge İİ, name into x").unwrap();
+        assert_eq!(
+            (merge.columns, merge.into),
+            (vec!["İİ".to_string(), "name".to_string()], "x".to_string())
+        );
+    }
+
+    #[test]
+    fn test_trimmed_header_names() {
+        let csv = "name , age \nAnn,30\nBob,41\n";
+        let results = run("people >> age  greater than 35 >> show name", csv);
+        assert_eq!(results, vec![vec!["name"], vec!["Bob"]]);
+        let results = run("people >> sort age desc >> show name , age", csv);
+        assert_eq!(column(&results, 1), vec!["41", "30"]);
+    }
+
+    #[test]
+    fn test_union_many_files() {
+        let dir = temp_dir("union");
+        // More files than a default open-file limit of 1024.
+        for i in 0..1500 {
+            std::fs::write(
+                dir.join(format!("part_{:04}.csv", i)),
+                format!("id\n{}\n", i),
+            )
+            .unwrap();
+        }
+        let query = parse(&dir.join("part_*.csv").display().to_string());
+        let (header_names, rows) = read_table(&query.table_name, &query).unwrap();
+        assert_eq!(header_names, vec!["id"]);
+        assert_eq!(rows.len(), 1500);
+        assert_eq!(rows[1499], vec!["1499"]);
+    }
+
+    #[test]
+    fn test_intersect() {
+        let first = run(
+            "a >> amount greater than 10",
+            "id,amount\n1,50\n2,5\n3,20\n4,30\n",
+        );
+        let second = run("b >> show id", "id,status\n4,open\n1,open\n9,open\n");
+        assert_eq!(
+            intersect_results(first, &second, "id").unwrap(),
+            vec![vec!["id", "amount"], vec!["1", "50"], vec!["4", "30"]]
+        );
+
+        let err = intersect_results(second.clone(), &second, "status").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Key column 'status' not in the first result"
+        );
+    }
+
+    #[test]
+    fn test_union_distinct() {
+        let dir = temp_dir("distinct");
+        std::fs::write(dir.join("a.csv"), "id,v\n1,x\n2,y\n").unwrap();
+        std::fs::write(dir.join("b.csv"), "id,v\n2,y\n3,z\n").unwrap();
+        let mut query = parse(&dir.join("*.csv").display().to_string());
+
+        let (_, rows) = read_table(&query.table_name, &query).unwrap();
+        assert_eq!(rows.len(), 4);
+
+        query.union_distinct = true;
+        let (_, rows) = read_table(&query.table_name, &query).unwrap();
+        assert_eq!(rows, vec![vec!["1", "x"], vec!["2", "y"], vec!["3", "z"]]);
+    }
+
+    #[test]
+    fn test_not_between() {
+        let csv = "id,amount\n1,5\n2,10\n3,15\n4,20\n5,25\n6,n/a\n";
+        let results = run("data >> amount not between 10 AND 20", csv);
+        // Inclusive bounds; text isn't in the range, so it is kept.
+        assert_eq!(column(&results, 0), vec!["1", "5", "6"]);
+        let results = run("data >> amount between 10 and 20", csv);
+        assert_eq!(column(&results, 0), vec!["2", "3", "4"]);
+
+        assert_eq!(parse_range("İİ and 2"), None);
+    }
+
+    #[test]
+    fn test_glance() {
+        let (header_names, rows) = table(
+            "id,name,joined\n1,Ann,2024-01-02\n2,,2024-02-03\n3,Bob,2024-03-04\n4,Cal,2024-04-05\n",
+        );
+        let results = glance_table(&header_names, &rows);
+        assert_eq!(results[0], vec!["column", "type", "examples"]);
+        assert_eq!(results[1], vec!["id", "integer", "1, 2, 3"]);
+        assert_eq!(results[2], vec!["name", "text", "Ann, Bob, Cal"]);
+        assert_eq!(
+            results[3],
+            vec!["joined", "date", "2024-01-02, 2024-02-03, 2024-03-04"]
+        );
+    }
+
+    #[test]
+    fn test_search_ranking() {
+        let (header_names, rows) = table(
+            "first,last,city\nJon,Smyth,Leeds\nJohn,Smith,York\nJoan,Smart,Hull\nMary,Jones,Bath\n",
+        );
+        let results = search_table(header_names, rows, "john smith", 2);
+        assert_eq!(results[0], vec!["score", "first", "last", "city"]);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[1], vec!["1.000", "John", "Smith", "York"]);
+        assert_eq!(results[2][1], "Jon");
+    }
+
+    #[test]
+    fn test_magnitude_filter() {
+        let csv = "amount\n5\n99.9\n100\n-450\n999.99\n1000\nabc\n0\n";
+        // Digits before the decimal point: 100 to 999.
+        let results = run("data >> where amount magnitude equals 3", csv);
+        assert_eq!(column(&results, 0), vec!["100", "-450", "999.99"]);
+        // Zero has no magnitude, and text never matches.
+        let results = run("data >> amount magnitude less than 2", csv);
+        assert_eq!(column(&results, 0), vec!["5"]);
+    }
+
+    #[test]
+    fn test_group_sort() {
+        let csv = "region,amount\nwest,5\neast,1\nnorth,2\neast,3\nwest,4\neast,6\n";
+        let grouped = |order: &str, input: &str| {
+            let mut query = parse(input);
+            query.group_sort = Some(order.to_string());
+            let (header_names, rows) = table(csv);
+            execute_query_on(&query, header_names, rows).unwrap()
+        };
+
+        let results = grouped("count", "sales >> group region count");
+        assert_eq!(column(&results, 0), vec!["east", "west", "north"]);
+        assert_eq!(column(&results, 1), vec!["3", "2", "1"]);
+        let results = grouped("key", "sales >> group region count");
+        assert_eq!(column(&results, 0), vec!["east", "north", "west"]);
+        let results = grouped("value-asc", "sales >> group region sum amount");
+        assert_eq!(column(&results, 0), vec!["north", "west", "east"]);
+        let results = grouped("value-desc", "sales >> group region sum amount");
+        assert_eq!(column(&results, 0), vec!["east", "west", "north"]);
+    }
+
+    #[test]
+    fn test_is_integer_and_float() {
+        let csv = "qty\n5\n5.0\n5.5\n-3\nfive\n\n";
+        let results = run("data >> where qty is integer", csv);
+        assert_eq!(column(&results, 0), vec!["5", "5.0", "-3"]);
+        let results = run("data >> where qty is float", csv);
+        assert_eq!(column(&results, 0), vec!["5.5"]);
+    }
+
+    #[test]
+    fn test_and_or_clauses() {
+        let csv = "id,salary,dept,title\n\
+                   1,60000,Sales,rock and roll\n\
+                   2,40000,Sales,jazz\n\
+                   3,70000,Ops,rock and roll\n\
+                   4,30000,Ops,blues or soul\n";
+        let results = run("t >> salary greater than 50000 and dept equals Sales", csv);
+        assert_eq!(column(&results, 0), vec!["1"]);
+        let results = run("t >> dept equals Ops or salary less than 50000", csv);
+        assert_eq!(column(&results, 0), vec!["2", "3", "4"]);
+        let results = run("t >> salary greater than 50000 >> dept equals Ops", csv);
+        assert_eq!(column(&results, 0), vec!["3"]);
+
+        // `and` / `or` inside a value, bare or quoted, stay part of it.
+        let results = run("t >> title contains rock and roll", csv);
+        assert_eq!(column(&results, 0), vec!["1", "3"]);
+        let results = run(
+            r#"t >> title contains "rock and roll" and dept equals Ops"#,
+            csv,
+        );
+        assert_eq!(column(&results, 0), vec!["3"]);
+        let results = run("t >> title equals 'blues or soul'", csv);
+        assert_eq!(column(&results, 0), vec!["4"]);
+    }
+
+    #[test]
+    fn test_exclude_from_glob() {
+        let dir = temp_dir("exclude");
+        for name in ["sales_jan", "sales_feb", "sales_template"] {
+            std::fs::write(
+                dir.join(format!("{}.csv", name)),
+                format!("month\n{}\n", name),
+            )
+            .unwrap();
+        }
+        let mut query = parse(&dir.join("sales_*.csv").display().to_string());
+        query.exclude = vec![glob::Pattern::new("sales_template.csv").unwrap()];
+        let (_, rows) = read_table(&query.table_name, &query).unwrap();
+        assert_eq!(rows, vec![vec!["sales_feb"], vec!["sales_jan"]]);
+    }
+
+    #[test]
+    fn test_first_last_nonempty() {
+        let csv = "user,email\nann,\nbob,\nann,ann@a.org\nann,ann@b.org\nbob,\nann,\n";
+        assert_eq!(
+            run("data >> group user first-nonempty email", csv)[1..],
+            [vec!["ann", "ann@a.org"], vec!["bob", ""]]
+        );
+        assert_eq!(
+            run("data >> group user last-nonempty email", csv)[1..],
+            [vec!["ann", "ann@b.org"], vec!["bob", ""]]
+        );
+    }
+
+    #[test]
+    fn test_json_path() {
+        let path = temp_dir("json-path").join("api.json");
+        std::fs::write(
+            &path,
+            r#"{"data": {"items": [{"id": 1, "user": {"name": "Ann"}}, {"id": 2, "user": {"name": "Bob"}}]}}"#,
+        )
+        .unwrap();
+        let mut query = parse(&format!("{} >> id greater than 1", path.display()));
+        query.json_path = Some("data.items".to_string());
+        assert_eq!(
+            execute_query(&query).unwrap(),
+            vec![vec!["id", "user.name"], vec!["2", "Bob"]]
+        );
+
+        query.json_path = Some("data".to_string());
+        assert!(execute_query(&query).is_err());
+    }
+
+    #[test]
+    fn test_operator_aliases() {
+        let path = temp_dir("aliases").join("operators.txt");
+        std::fs::write(&path, "# Spanish\nigual = equals\nmayor = greater than\n").unwrap();
+        let aliases = load_operator_aliases(path.to_str().unwrap()).unwrap();
+
+        let query = parse_query(
+            "t >> dept IGUAL Ventas y age mayor 30",
+            None,
+            false,
+            &aliases,
+        )
+        .unwrap();
+        assert_eq!(query.filters.len(), 1);
+        assert_eq!(query.filters[0].operator, "equals");
+        assert_eq!(query.filters[0].value, "Ventas y age mayor 30");
+
+        let query = parse_query(
+            "t >> dept igual Ventas and age mayor 30",
+            None,
+            false,
+            &aliases,
+        )
+        .unwrap();
+        let operators: Vec<&str> = query.filters.iter().map(|f| f.operator.as_str()).collect();
+        assert_eq!(operators, vec!["equals", "greater"]);
+        let (header_names, rows) = table("dept,age\nVentas,40\nVentas,20\nRRHH,50\n");
+        let results = execute_query_on(&query, header_names, rows).unwrap();
+        assert_eq!(results[1..], [vec!["Ventas", "40"]]);
+    }
+
+    #[test]
+    fn test_warnings_returned_to_caller() {
+        let query = parse("t >> sum price");
+        let (header_names, rows) = table("price\n3\nn/a\n4\n");
+        let results = execute_query_on(&query, header_names, rows).unwrap();
+        assert_eq!(results[1], vec!["7"]);
+        assert_eq!(
+            query.notices.take(),
+            vec![Notice::Warning(
+                "skipped 1 non-numeric values in 'price'".to_string()
+            )]
+        );
+        assert!(query.notices.take().is_empty());
+
+        let missing = read_table("/nonexistent/flexiql.csv", &query).unwrap_err();
+        assert!(matches!(missing, Error::Io(_)));
+        assert!(std::error::Error::source(&missing).is_some());
+        assert!(std::error::Error::source(&Error::Parse("bad".into())).is_none());
+    }
+
+    #[cfg(feature = "hash")]
+    #[test]
+    fn test_hash_columns() {
+        let results = run(
+            "t >> hash first, last as key >> show key",
+            "first,last\nab,c\na,bc\nab,c\n",
+        );
+        let keys = column(&results, 0);
+        assert_eq!(keys[0], keys[2]);
+        assert_ne!(keys[0], keys[1]);
+        assert_eq!(keys[0].len(), 64);
+
+        assert!(parse_query("t >> hash İİ as x", None, false, &HashMap::new()).is_ok());
+        assert!(parse_query("t >> hash İİ", None, false, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_shards_cover_all_rows() {
+        let dir = temp_dir("shard");
+        let path = dir.join("t.csv");
+        std::fs::write(&path, "id\n1\n2\n3\n4\n5\n").unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let mut ids = Vec::new();
+        for shard in ["1/2", "2/2"] {
+            let mut query = parse(&path);
+            query.shard = Some(parse_shard(shard).unwrap());
+            let (_, rows) = read_table(&path, &query).unwrap();
+            ids.extend(rows.into_iter().map(|row| row[0].clone()));
+        }
+        ids.sort();
+        assert_eq!(ids, vec!["1", "2", "3", "4", "5"]);
+
+        assert!(parse_shard("3/2").is_err());
+        assert!(parse_shard("0/2").is_err());
+    }
+
+    #[test]
+    fn test_lossy_utf8() {
+        let dir = temp_dir("lossy");
+        let path = dir.join("t.csv");
+        std::fs::write(&path, b"name,city\nAnn,Z\xfcrich\nBo,Oslo\n").unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let mut query = parse(&format!("{} >> show city", path));
+        assert!(matches!(read_table(&path, &query), Err(Error::Csv(_))));
+
+        query.lossy_utf8 = true;
+        let results = execute_query(&query).unwrap();
+        assert_eq!(column(&results, 0), vec!["Z\u{fffd}rich", "Oslo"]);
+        assert_eq!(
+            query.notices.take(),
+            vec![Notice::Warning(format!(
+                "replaced invalid UTF-8 in 1 fields of '{}'",
+                path
+            ))]
+        );
+    }
+
+    #[test]
+    fn test_cumsum_running_total() {
+        let query = parse("sales >> sort date >> cumsum amount as running_total");
+        let (header_names, rows) =
+            table("date,amount\n2024-01-03,5\n2024-01-01,10\n2024-01-02,n/a\n2024-01-04,2.5\n");
+        let results = execute_query_on(&query, header_names, rows).unwrap();
+        assert_eq!(results[0], vec!["date", "amount", "running_total"]);
+        assert_eq!(column(&results, 2), vec!["10", "10", "15", "17.5"]);
+        assert_eq!(
+            query.notices.take(),
+            vec![Notice::Warning(
+                "counted 1 non-numeric values in 'amount' as 0".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_zip_entry() {
+        use std::io::Write;
+
+        let dir = temp_dir("zip");
+        let path = dir.join("data.zip");
+        let mut archive = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        archive
+            .start_file("employees.csv", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        archive.write_all(b"name,dept\nAnn,Sales\nBo,IT\n").unwrap();
+        archive.finish().unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let results =
+            execute_query(&parse(&format!("{}!employees.csv >> show name", path))).unwrap();
+        assert_eq!(column(&results, 0), vec!["Ann", "Bo"]);
+
+        let missing = execute_query(&parse(&format!("{}!missing.csv", path))).unwrap_err();
+        assert_eq!(
+            missing.to_string(),
+            format!("No entry 'missing.csv' in '{}'", path)
+        );
+    }
+
+    #[test]
+    fn test_is_not_numeric() {
+        let results = run(
+            "data >> where amount is not numeric >> show id",
+            "id,amount\n1,10\n2,1O\n3,7.5\n4,n/a\n5,-3\n",
+        );
+        assert_eq!(column(&results, 0), vec!["2", "4"]);
+    }
+
+    #[test]
+    fn test_sort_mixed_directions() {
+        let query = parse("t >> sort dept asc, salary desc, name");
+        assert_eq!(
+            query.sort_keys,
+            vec![
+                ("dept".to_string(), false),
+                ("salary".to_string(), true),
+                ("name".to_string(), false),
+            ]
+        );
+
+        let results = run(
+            "t >> sort dept asc, salary desc, name >> show name",
+            "name,dept,salary\nCy,IT,50\nAnn,Sales,40\nBo,IT,70\nAl,IT,50\nDi,Sales,60\n",
+        );
+        assert_eq!(column(&results, 0), vec!["Bo", "Al", "Cy", "Di", "Ann"]);
+    }
+
+    #[test]
+    fn test_modulo_filter() {
+        let results = run(
+            "data >> where id mod 2 equals 0 >> show id",
+            "id\n1\n2\n3\n4\n4.5\nx\n10\n",
+        );
+        assert_eq!(column(&results, 0), vec!["2", "4", "10"]);
+    }
+
+    #[test]
+    fn test_run_query_in_memory() {
+        let csv = "name;age\nAnn;31\nBo;25\nCy;40\n";
+        let results =
+            run_query("people >> age greater than 30 >> show name", csv.as_bytes()).unwrap();
+        assert_eq!(results, vec![vec!["name"], vec!["Ann"], vec!["Cy"]]);
+
+        let results = run_query(">> count", csv.as_bytes()).unwrap();
+        assert_eq!(results[1], vec!["3"]);
+
+        assert!(matches!(
+            run_query(">> show height", csv.as_bytes()),
+            Err(Error::Query(_))
+        ));
+    }
+
+    #[test]
+    fn test_two_row_header() {
+        let dir = temp_dir("header-rows");
+        let path = dir.join("t.csv");
+        std::fs::write(&path, "Q1,Q1,Q2\nsales,costs,sales\n10,4,12\n").unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let mut query = parse(&format!("{} >> show Q2 / sales", path));
+        query.header_rows = 2;
+        let (header_names, rows) = read_table(&path, &query).unwrap();
+        assert_eq!(header_names, vec!["Q1 / sales", "Q1 / costs", "Q2 / sales"]);
+        assert_eq!(rows, vec![vec!["10", "4", "12"]]);
+
+        let results = execute_query(&query).unwrap();
+        assert_eq!(column(&results, 0), vec!["12"]);
+    }
+
+    #[test]
+    fn test_plan_round_trip() {
+        let dir = temp_dir("plan");
+        let path = dir.join("t.csv");
+        std::fs::write(&path, "name,age\nAnn,31\nBo,25\nCy,40\n").unwrap();
+        let input = format!(
+            "{} >> age greater than 28 >> sort age desc >> show name",
+            path.display()
+        );
+        let query = parse(&input);
+        let plan = save_plan(&query).unwrap();
+        let loaded = load_plan(&plan).unwrap();
+        assert_eq!(
+            execute_query(&loaded).unwrap(),
+            execute_query(&query).unwrap()
+        );
+        assert_eq!(save_plan(&loaded).unwrap(), plan);
+
+        // A saved `today-7d` means seven days before the day the plan runs.
+        let plan = save_plan(&parse(
+            "t >> date greater than today-7d >> since today on date",
+        ))
+        .unwrap();
+        assert!(plan.contains("\"today-7d\""));
+        let later = NaiveDate::from_ymd_opt(2030, 1, 10).unwrap();
+        let loaded = load_plan_at(&plan, later).unwrap();
+        assert_eq!(loaded.filters[0].value, "2030-01-03");
+        assert_eq!(loaded.filters[1].value, "2030-01-10");
+    }
+
+    #[test]
+    fn test_above_p95() {
+        let csv: String = std::iter::once("id,amount".to_string())
+            .chain((1..=100).map(|i| format!("{},{}", i, i * 10)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let results = run("data >> where amount above p95 >> show id", &csv);
+        assert_eq!(column(&results, 0), vec!["96", "97", "98", "99", "100"]);
+
+        let results = run("data >> where amount below p5 >> show id", &csv);
+        assert_eq!(column(&results, 0), vec!["1", "2", "3", "4", "5"]);
+    }
+
+    #[test]
+    fn test_union_source_column() {
+        let dir = temp_dir("source-column");
+        std::fs::write(dir.join("2023.csv"), "name\nAnn\n").unwrap();
+        std::fs::write(dir.join("2024.csv"), "name\nBo\nCy\n").unwrap();
+        let pattern = dir.join("*.csv").to_string_lossy().into_owned();
+
+        let mut query = parse(&format!("{} >> show name, source", pattern));
+        query.source_column = Some("source".to_string());
+        let results = execute_query(&query).unwrap();
+        let file = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        assert_eq!(column(&results, 0), vec!["Ann", "Bo", "Cy"]);
+        assert_eq!(
+            column(&results, 1),
+            vec![file("2023.csv"), file("2024.csv"), file("2024.csv")]
+        );
+    }
+
+    #[test]
+    fn test_show_default() {
+        let dir = temp_dir("show-default");
+        std::fs::write(dir.join("a.csv"), "name,region\nAnn,EU\nBo,\n").unwrap();
+        std::fs::write(dir.join("b.csv"), "name\nCy\n").unwrap();
+
+        let name = |file: &str| dir.join(file).to_string_lossy().into_owned();
+        let results = execute_query(&parse(&format!(
+            r#"{} >> show name, region default "N/A""#,
+            name("a.csv")
+        )))
+        .unwrap();
+        assert_eq!(column(&results, 1), vec!["EU", "N/A"]);
+
+        let results = execute_query(&parse(&format!(
+            r#"{} >> show name, region default "N/A""#,
+            name("b.csv")
+        )))
+        .unwrap();
+        assert_eq!(results, vec![vec!["name", "region"], vec!["Cy", "N/A"]]);
+
+        assert!(parse_query("t >> show name éééé", None, false, &HashMap::new()).is_ok());
+    }
+
+    #[test]
+    fn test_sort_precision() {
+        let (header_names, rows) = table("id,value\na,1.0000001\nb,0.5\nc,1.0\n");
+        let mut query = parse("t >> sort value >> show id, value");
+        let results = execute_query_on(&query, header_names.clone(), rows.clone()).unwrap();
+        assert_eq!(column(&results, 0), vec!["b", "c", "a"]);
+
+        // Rounded to 3 places the two ones tie and keep their file order.
+        query.sort_precision = Some(3);
+        let results = execute_query_on(&query, header_names.clone(), rows.clone()).unwrap();
+        assert_eq!(column(&results, 0), vec!["b", "a", "c"]);
+        assert_eq!(column(&results, 1), vec!["0.5", "1.0000001", "1.0"]);
+
+        let mut query = parse("t >> sort value, id desc >> show id");
+        query.sort_precision = Some(3);
+        let results = execute_query_on(&query, header_names, rows).unwrap();
+        assert_eq!(column(&results, 0), vec!["b", "c", "a"]);
+    }
+
+    #[test]
+    fn test_apostrophe_in_value() {
+        let csv = "name,age,owner's team\nO'Brien,30,A\nAnn,40,B\nBo,20,C\n";
+        let results = run(
+            "t >> name equals O'Brien or age greater than 35 >> show name",
+            csv,
+        );
+        assert_eq!(column(&results, 0), vec!["O'Brien", "Ann"]);
+
+        let results = run("t >> name equals O'Brien >> show owner's team, name", csv);
+        assert_eq!(
+            results,
+            vec![vec!["owner's team", "name"], vec!["A", "O'Brien"]]
+        );
+
+        let query = parse("t >> show owner's team default none");
+        assert_eq!(query.columns, Some(vec!["owner's team".to_string()]));
+    }
+}
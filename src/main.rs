@@ -1,330 +1,2042 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use colored::*;
+use flexiql::*;
 use std::collections::HashMap;
 use std::error::Error;
-
-#[derive(Debug)]
-struct Query {
-    table_name: String,
-    filter: Option<Filter>,
-    columns: Option<Vec<String>>,
-    sort_column: Option<String>,
-    sort_desc: bool,
-    limit: Option<usize>,
-}
-
-#[derive(Debug)]
-struct Filter {
-    column: String,
-    operator: String,
-    value: String,
-}
+use std::io::{IsTerminal, Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+use unicode_width::UnicodeWidthStr;
 
 #[derive(Parser)]
 #[command(name = "flexiql")]
 #[command(about = "A simple CSV query language")]
 struct Cli {
-    query: String,
-}
+    /// The query; a `-` table (or none) reads the table from stdin
+    #[arg(allow_hyphen_values = true)]
+    query: Option<String>,
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
-    let query = parse_query(&cli.query)?;
+    /// With two arguments, the first is the table and this is the rest of the
+    /// query, e.g. `flexiql employees "salary greater than 50000 >> show name"`
+    #[arg(value_name = "STAGES", allow_hyphen_values = true)]
+    stages: Option<String>,
 
-    let results = execute_query(query)?;
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    print_results(results);
+    /// Table used when the query starts with `>>` instead of a table name
+    #[arg(long, env = "AQL_TABLE")]
+    table: Option<String>,
 
-    Ok(())
+    /// Directory that `split-by` writes its files into
+    #[arg(
+        long,
+        visible_alias = "output-dir",
+        value_name = "DIR",
+        default_value = "."
+    )]
+    out_dir: String,
+
+    /// File name pattern for `split-by`, e.g. "{region}_{year}.csv"
+    #[arg(long, value_name = "TEMPLATE")]
+    name_template: Option<String>,
+
+    /// Keep watching the table and print matching rows as they are appended
+    #[arg(long)]
+    follow: bool,
+
+    /// Let the last column of a `split` stage keep the rest of the value instead of dropping extra parts
+    #[arg(long)]
+    split_remainder: bool,
+
+    /// Remove the source columns of a `merge` stage
+    #[arg(long)]
+    merge_drop: bool,
+
+    /// Separator used by the group-concat aggregate
+    #[arg(long, value_name = "SEP", default_value = ", ")]
+    concat_sep: String,
+
+    /// File of `alias = operator` lines adding other names for filter operators
+    #[arg(long, value_name = "PATH")]
+    operators_file: Option<String>,
+
+    /// Save the parsed query, with the settings from these flags, as a JSON plan
+    #[arg(long, value_name = "PATH")]
+    save_plan: Option<String>,
+
+    /// Run a plan saved with --save-plan instead of a query
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["query", "save_plan"])]
+    load_plan: Option<String>,
+
+    /// Reject stages that look like a misspelled keyword instead of treating them as filters
+    #[arg(long)]
+    strict_parse: bool,
+
+    /// Order of grouped rows; by default groups appear in the order they are first seen
+    #[arg(long, value_parser = ["key", "count", "value-asc", "value-desc"])]
+    group_sort: Option<String>,
+
+    /// How sum, avg, min and max treat empty cells: skip them, count them as 0, or fail
+    #[arg(long, default_value = "skip", value_parser = ["skip", "zero", "error"])]
+    agg_nulls: String,
+
+    /// Comma separated cell values that `is true` matches
+    #[arg(long, value_name = "LIST", default_value = DEFAULT_TRUE_VALUES)]
+    true_values: String,
+
+    /// Comma separated cell values that `is false` matches
+    #[arg(long, value_name = "LIST", default_value = DEFAULT_FALSE_VALUES)]
+    false_values: String,
+
+    /// Fail when an `in` / `not in` value never occurs in its column
+    #[arg(long)]
+    strict: bool,
+
+    /// Only use the first N rows to work out column widths
+    #[arg(long, value_name = "K")]
+    width_sample: Option<usize>,
+
+    /// Highlight the text matched by a `contains` filter
+    #[arg(long)]
+    highlight: bool,
+
+    /// Disable colored output
+    #[arg(long)]
+    no_color: bool,
+
+    /// Exit with an error when the table has no data rows (before filtering)
+    #[arg(long)]
+    fail_if_empty: bool,
+
+    /// Drop identical rows when a table pattern reads several files (default keeps them all)
+    #[arg(long)]
+    union_distinct: bool,
+
+    /// Add a column with the given name holding each row's file when a table pattern reads several files
+    #[arg(long, value_name = "NAME")]
+    with_source_column: Option<String>,
+
+    /// Replace invalid UTF-8 in tables with U+FFFD instead of failing, with a warning
+    #[arg(long)]
+    lossy_utf8: bool,
+
+    /// Number of rows that make up the header; their names are joined per column
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    header_rows: usize,
+
+    /// Separator between the parts of a multi-row header name
+    #[arg(long, value_name = "TEXT", default_value = " / ")]
+    header_join: String,
+
+    /// Read only shard K of N, e.g. 2/5; the N shards together cover every row once
+    #[arg(long, value_name = "K/N")]
+    shard: Option<String>,
+
+    /// Dotted path to the array of rows in a `.json` table, e.g. data.items
+    #[arg(long, value_name = "PATH")]
+    json_path: Option<String>,
+
+    /// Leave files matching this pattern out of a table pattern; can be repeated
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Field delimiter: a single character, `tab`, or `auto` to detect it.
+    /// Defaults to `,`, or `;` under `--locale eu`
+    #[arg(long)]
+    delimiter: Option<String>,
+
+    /// Round numbers to N decimals when sorting, so near-equal values tie; the shown values are unchanged
+    #[arg(long, value_name = "N")]
+    sort_precision: Option<u32>,
+
+    /// Number format: `eu` reads `3,14` as 3.14 and defaults the delimiter to `;`
+    #[arg(long, default_value = "en", value_parser = ["en", "eu"])]
+    locale: String,
+
+    /// Report details such as the detected delimiter on stderr
+    #[arg(long)]
+    verbose: bool,
+
+    /// Stop printing once the output reaches this many bytes
+    #[arg(long, value_name = "N")]
+    max_output_bytes: Option<usize>,
+
+    /// Print at most N lines of table or plain output, then say how many rows were left out.
+    /// Defaults to the terminal height when writing to a terminal
+    #[arg(long, value_name = "N")]
+    max_lines: Option<usize>,
+
+    /// Palette used for table colors
+    #[arg(long, default_value = "16", value_parser = ["16", "256", "truecolor"])]
+    color_depth: String,
+
+    /// Output format; `kv` prints each row as `col=value ...` on one line, `arrow` writes an Arrow IPC file and needs --output
+    #[arg(long, default_value = "table", value_parser = ["table", "plain", "kv", "yaml", "sql", "json", "csv", "arrow"])]
+    format: String,
+
+    /// Shape of `--format json`: an object per row, or an array per column
+    #[arg(long, default_value = "rows", value_parser = ["rows", "columns"])]
+    orient: String,
+
+    /// Table named in `--format sql` INSERT statements; defaults to the queried table
+    #[arg(long, value_name = "NAME")]
+    table_name: Option<String>,
+
+    /// Rows per INSERT statement with `--format sql`
+    #[arg(long, value_name = "N", default_value = "1")]
+    sql_batch: std::num::NonZeroUsize,
+
+    /// Write the results to a file instead of stdout
+    #[arg(long, value_name = "PATH")]
+    output: Option<String>,
+
+    /// Also write the number of result rows to this file, replacing it atomically
+    #[arg(long, value_name = "PATH")]
+    count_file: Option<String>,
+
+    /// Print a checksum of the result rows to stderr, the same for every output format
+    #[arg(long)]
+    checksum: bool,
+
+    /// Number the rows in a leading `#` column (table and plain output only)
+    #[arg(long)]
+    index: bool,
+
+    /// Blank cells repeating the cell above them (table and plain output only)
+    #[arg(long)]
+    delta_columns: bool,
+
+    /// Add a line with each column's inferred type under the table header
+    #[arg(long)]
+    show_types: bool,
+
+    /// Character encoding of the output, e.g. latin1 or shift_jis
+    #[arg(long, value_name = "LABEL", default_value = "utf-8")]
+    output_encoding: String,
+
+    /// Append to the --output file, leaving out the header if it already has content
+    #[arg(long, requires = "output")]
+    append: bool,
 }
 
-fn parse_query(input: &str) -> Result<Query, String> {
-    let parts: Vec<&str> = input.split(">>").map(|s| s.trim()).collect();
+#[derive(Subcommand)]
+enum Command {
+    /// Rows of the first query whose key also appears in the second query's result
+    Intersect {
+        first: String,
+        second: String,
 
-    if parts.is_empty() {
-        return Err("Empty query".to_string());
-    }
+        /// Column both results are matched on
+        #[arg(long)]
+        key: String,
+    },
+    /// Each column's name, inferred type and a few example values
+    Glance {
+        table: String,
 
-    // First part is always the table name
-    let table_name = parts[0].to_string();
+        /// Number of rows the types and examples are taken from
+        #[arg(long, value_name = "N", default_value_t = 100)]
+        sample: usize,
+    },
+    /// Rows ranked by how closely they match the search words, in any column
+    Search {
+        table: String,
+        text: String,
 
-    let mut query = Query {
-        table_name: format!("{}.csv", table_name),
-        filter: None,
-        columns: None,
-        sort_column: None,
-        sort_desc: false,
-        limit: None,
-    };
+        /// Number of rows to show
+        #[arg(long, value_name = "N", default_value_t = 10)]
+        top: usize,
+    },
+}
 
-    for part in &parts[1..] {
-        let words: Vec<&str> = part.split_whitespace().collect();
+// Settings for the table renderer that come from the command line.
+struct TableOptions<'a> {
+    width_sample: Option<usize>,
+    highlight: Option<&'a Filter>,
+    color_depth: &'a str,
+    // Leave out the header lines, e.g. when appending to an existing file.
+    skip_header: bool,
+    // Printing to a terminal: an empty result gets a friendly message
+    // instead of a bare header.
+    interactive: bool,
+    // `--show-types`: a line of column types under the header.
+    column_types: Option<Vec<String>>,
+}
 
-        if words.is_empty() {
-            continue;
-        }
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-        match words[0].to_lowercase().as_str() {
-            "show" => {
-                let columns_str = part.strip_prefix("show").unwrap().trim();
-                let columns: Vec<String> = columns_str
-                    .split(',')
-                    .map(|s| s.trim().to_string())
-                    .collect();
-                query.columns = Some(columns);
+fn run() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    if cli.no_color || cli.output.is_some() {
+        colored::control::set_override(false);
+    }
+
+    let (query, mut results) = match &cli.command {
+        Some(Command::Intersect { first, second, key }) => {
+            if cli.follow {
+                return Err("--follow can't be used with intersect".into());
             }
-            "sort" => {
-                if words.len() >= 2 {
-                    query.sort_column = Some(words[1].to_string());
-                    if words.len() >= 3 && words[2].to_lowercase() == "desc" {
-                        query.sort_desc = true;
-                    }
-                }
+            let query = prepare_query(first, &cli)?;
+            let other = prepare_query(second, &cli)?;
+            let results = intersect_results(execute_query(&query)?, &execute_query(&other)?, key)?;
+            print_notices(&other.notices);
+            (query, results)
+        }
+        Some(Command::Glance { table, sample }) => {
+            if cli.follow {
+                return Err("--follow can't be used with glance".into());
             }
-            "take" | "limit" => {
-                if words.len() >= 2 {
-                    query.limit = words[1].parse().ok();
-                }
+            let query = prepare_query(table, &cli)?;
+            let (header_names, rows) = read_table(&query.table_name, &query)?;
+            let results = glance_table(&header_names, &rows[..rows.len().min(*sample)]);
+            (query, results)
+        }
+        Some(Command::Search { table, text, top }) => {
+            if cli.follow {
+                return Err("--follow can't be used with search".into());
             }
-            _ => {
-                query.filter = parse_filter(part)?;
+            let query = prepare_query(table, &cli)?;
+            let (header_names, rows) = read_table(&query.table_name, &query)?;
+            (query, search_table(header_names, rows, text, *top))
+        }
+        None if cli.load_plan.is_some() => {
+            let path = cli.load_plan.as_deref().unwrap_or_default();
+            let plan = std::fs::read_to_string(path)
+                .map_err(|e| format!("Can't read plan {}: {}", path, e))?;
+            let query = load_plan(&plan)?;
+            let results = execute_query(&query)?;
+            (query, results)
+        }
+        None => {
+            let Some(input) = query_input(&cli) else {
+                return Err("Missing query".into());
+            };
+            let query = prepare_query(&input, &cli)?;
+            if let Some(path) = &cli.save_plan {
+                std::fs::write(path, save_plan(&query)? + "\n")?;
             }
+            let results = execute_query(&query)?;
+            (query, results)
+        }
+    };
+    print_notices(&query.notices);
+    let split_by = query.split_by.clone();
+    let highlight = query
+        .filters
+        .iter()
+        .chain(query.or_filters.iter().flatten().flatten())
+        .find(|f| cli.highlight && (f.operator == "contains" || f.operator == "matches"));
+
+    // Anything past this point in the file is picked up by `--follow`.
+    let end_offset = if cli.follow {
+        if is_glob(&query.table_name) {
+            return Err("--follow needs a single table, not a pattern".into());
+        }
+        if query.table_name == STDIN_TABLE {
+            return Err("--follow can't be used with a table read from stdin".into());
+        }
+        if query.shard.is_some() {
+            return Err("--follow can't be used with --shard".into());
+        }
+        if query.header_rows > 1 {
+            return Err("--follow can't be used with --header-rows".into());
         }
+        if let Some(stage) = follow_conflict(&query) {
+            return Err(format!("--follow can't be used with {}", stage).into());
+        }
+        if cli.format != "table" && cli.format != "plain" {
+            return Err(format!("--follow can't be used with --format {}", cli.format).into());
+        }
+        std::fs::metadata(&query.table_name)?.len()
+    } else {
+        0
+    };
+
+    let total = results.len().saturating_sub(1);
+    if let Some(path) = &cli.count_file {
+        write_count_file(path, total)?;
+    }
+    if cli.checksum {
+        eprintln!("Checksum: {:016x}", result_checksum(&results));
     }
 
-    Ok(query)
-}
+    if let Some(columns) = split_by {
+        let template = cli.name_template.clone().unwrap_or_else(|| {
+            let placeholders: Vec<String> = columns.iter().map(|c| format!("{{{}}}", c)).collect();
+            format!("{}.csv", placeholders.join("_"))
+        });
+        results = write_split_files(results, &template, &cli.out_dir)?;
+    }
+
+    // Lines around the rows: header, separator, blank line and row count for
+    // the table, the header for plain output (kv has none), plus the notice
+    // itself, the `with-count` total and the `--show-types` line.
+    let overhead = match cli.format.as_str() {
+        "table" => Some(5 + usize::from(query.with_count) + usize::from(cli.show_types)),
+        "plain" => Some(2 + usize::from(query.with_count)),
+        "kv" => Some(1 + usize::from(query.with_count)),
+        _ => None,
+    };
+    let max_lines = cli.max_lines.or_else(|| {
+        let interactive = cli.output.is_none() && !cli.follow && std::io::stdout().is_terminal();
+        interactive
+            .then(terminal_size::terminal_size)
+            .flatten()
+            .map(|(_, terminal_size::Height(height))| usize::from(height))
+    });
+    let hidden_rows = match (max_lines, overhead) {
+        (Some(max_lines), Some(overhead)) => fit_to_lines(&mut results, max_lines, overhead),
+        _ => 0,
+    };
 
-fn parse_filter(filter_str: &str) -> Result<Option<Filter>, String> {
-    let words: Vec<&str> = filter_str.split_whitespace().collect();
+    // Display transforms for the aligned formats; kv keeps the rows as they are.
+    let aligned = matches!(cli.format.as_str(), "table" | "plain");
+    if cli.delta_columns && aligned {
+        if cli.follow {
+            return Err("--delta-columns can't be used with --follow".into());
+        }
+        blank_repeated_cells(&mut results);
+    }
 
-    if words.len() < 3 {
-        return Err(format!("Invalid filter: {}", filter_str));
+    if cli.index && aligned {
+        if cli.follow {
+            return Err("--index can't be used with --follow".into());
+        }
+        add_index_column(&mut results);
     }
 
-    let column = words[0].to_string();
-    let (operator, value_start_index) = {
-        if words.len() >= 4 && words[1] == "greater" && words[2] == "than" {
-            ("greater".to_string(), 3)
-        } else if words.len() >= 4 && words[1] == "less" && words[2] == "than" {
-            ("less".to_string(), 3)
-        } else if words[1] == "equals" {
-            ("equals".to_string(), 2)
-        } else if words[1] == "contains" {
-            ("contains".to_string(), 2)
-        } else {
-            (words[1].to_string(), 2)
+    // Types of the rows actually shown, so after --max-lines.
+    let column_types = (cli.show_types && cli.format == "table").then(|| column_types(&results));
+
+    let follow_widths = cli.follow.then(|| {
+        let mut col_widths = column_widths(&results, cli.width_sample);
+        if let Some(types) = &column_types {
+            fit_widths(&mut col_widths, types);
+        }
+        col_widths
+    });
+
+    let encoding = output_encoding(&cli.output_encoding, &cli.format)?;
+
+    if cli.format == "arrow" {
+        if cfg!(not(feature = "arrow")) {
+            return Err("--format arrow needs flexiql built with the 'arrow' feature".into());
+        }
+        if cli.output.is_none() || cli.append {
+            return Err(
+                "--format arrow writes a new file; give it --output without --append".into(),
+            );
+        }
+    }
+
+    let mut skip_header = false;
+    let mut sink: Box<dyn Write> = match &cli.output {
+        Some(path) => {
+            let file;
+            (file, skip_header) = open_output(path, cli.append)?;
+            Box::new(std::io::BufWriter::new(file))
         }
+        None => Box::new(std::io::stdout().lock()),
     };
+    if encoding != encoding_rs::UTF_8 {
+        sink = Box::new(TranscodingWriter::new(sink, encoding));
+    }
+    let mut out = CappedWriter::new(sink, cli.max_output_bytes);
+
+    match cli.format.as_str() {
+        "plain" => print_plain(&mut out, &results, skip_header)?,
+        "kv" => print_kv(&mut out, &results)?,
+        "yaml" => print_yaml(&mut out, &results, skip_header)?,
+        "json" => {
+            let total = query.with_count.then_some(total);
+            print_json(&mut out, &results, &cli.orient, total)?
+        }
+        "csv" => print_csv(
+            &mut out,
+            &results,
+            skip_header,
+            query.delimiter.unwrap_or(b','),
+        )?,
+        #[cfg(feature = "arrow")]
+        "arrow" => print_arrow(&mut out, &results)?,
+        "sql" => {
+            let table_name = cli.table_name.clone().unwrap_or_else(|| {
+                std::path::Path::new(&query.table_name)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| query.table_name.clone())
+            });
+            print_sql(&mut out, &results, &table_name, cli.sql_batch.get())?
+        }
+        _ => {
+            let options = TableOptions {
+                width_sample: cli.width_sample,
+                highlight,
+                color_depth: &cli.color_depth,
+                skip_header,
+                interactive: cli.output.is_none() && std::io::stdout().is_terminal(),
+                column_types,
+            };
+            print_results(&mut out, results, &options)?
+        }
+    }
+
+    if hidden_rows > 0 {
+        writeln!(out, "{}", format!("… ({} more rows)", hidden_rows).dimmed())?;
+    }
 
-    if words.len() <= value_start_index {
-        return Err(format!("Missing value in filter: {}", filter_str));
+    if query.with_count {
+        print_total(&mut out, &mut std::io::stderr(), &cli.format, total)?;
     }
-    let value = words[value_start_index..].join(" ");
 
-    Ok(Some(Filter {
-        column,
-        operator,
-        value,
-    }))
+    if let Some(col_widths) = follow_widths {
+        let separator = if cli.format == "plain" { "  " } else { " | " };
+        follow_table(&mut out, &query, end_offset, &col_widths, separator)?;
+    }
+
+    out.finish()?;
+
+    Ok(())
 }
 
-fn execute_query(query: Query) -> Result<Vec<Vec<String>>, Box<dyn Error>> {
-    let mut reader = csv::Reader::from_path(&query.table_name)?;
-    let headers = reader.headers()?.clone();
-    let header_names: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+// Drops the rows that don't fit in `max_lines` next to `overhead` other lines,
+// keeping at least one, and returns how many were dropped.
+fn fit_to_lines(results: &mut Vec<Vec<String>>, max_lines: usize, overhead: usize) -> usize {
+    let shown = max_lines.saturating_sub(overhead).max(1);
+    if results.len() <= shown + 1 {
+        return 0;
+    }
+    let hidden_rows = results.len() - shown - 1;
+    results.truncate(shown + 1);
+    hidden_rows
+}
+
+// Opens the `--output` file, and says whether its header is already there:
+// appending to a file with content leaves the header out.
+fn open_output(path: &str, append: bool) -> std::io::Result<(std::fs::File, bool)> {
+    let skip_header = append && std::fs::metadata(path).is_ok_and(|m| m.len() > 0);
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?;
+    Ok((file, skip_header))
+}
+
+// Parses `input` and applies the settings that come from the command line.
+// The query text, with the two-argument form `<table> <stages>` joined into
+// one `<table> >> <stages>` query.
+fn query_input(cli: &Cli) -> Option<String> {
+    let input = cli.query.as_ref()?;
+    Some(match &cli.stages {
+        Some(stages) => {
+            let stages = stages.trim_start();
+            let stages = stages.strip_prefix(">>").unwrap_or(stages).trim_start();
+            format!("{} >> {}", input, stages)
+        }
+        None => input.clone(),
+    })
+}
 
-    let mut header_map = HashMap::new();
-    for (i, header) in header_names.iter().enumerate() {
-        header_map.insert(header.clone(), i);
+fn prepare_query(input: &str, cli: &Cli) -> Result<Query, Box<dyn Error>> {
+    let operator_aliases = match &cli.operators_file {
+        Some(path) => load_operator_aliases(path)?,
+        None => HashMap::new(),
+    };
+    let mut query = parse_query(
+        input,
+        cli.table.as_deref(),
+        cli.strict_parse,
+        &operator_aliases,
+    )?;
+    query.split_remainder = cli.split_remainder;
+    query.merge_drop = cli.merge_drop;
+    query.concat_separator = cli.concat_sep.clone();
+    query.group_sort = cli.group_sort.clone();
+    query.agg_nulls = cli.agg_nulls.clone();
+    query.strict = cli.strict;
+    query.fail_if_empty = cli.fail_if_empty;
+    query.union_distinct = cli.union_distinct;
+    query.source_column = cli.with_source_column.clone();
+    query.lossy_utf8 = cli.lossy_utf8;
+    query.header_rows = cli.header_rows;
+    query.header_join = cli.header_join.clone();
+    query.shard = cli.shard.as_deref().map(parse_shard).transpose()?;
+    query.json_path = cli.json_path.clone();
+    query.exclude = cli
+        .exclude
+        .iter()
+        .map(|pattern| glob::Pattern::new(pattern))
+        .collect::<Result<_, _>>()?;
+    query.sort_precision = cli.sort_precision;
+    query.decimal_comma = cli.locale == "eu";
+    query.delimiter = match &cli.delimiter {
+        Some(delimiter) => parse_delimiter(delimiter)?,
+        None if query.decimal_comma => Some(b';'),
+        None => Some(b','),
+    };
+    query.verbose = cli.verbose;
+    let decimal_comma = query.decimal_comma;
+    let filters = query
+        .filters
+        .iter_mut()
+        .chain(query.or_filters.iter_mut().flatten().flatten());
+    for filter in filters {
+        filter.decimal_comma = decimal_comma;
+        let tokens = match (filter.operator.as_str(), filter.value.as_str()) {
+            ("is", "true") => &cli.true_values,
+            ("is", "false") => &cli.false_values,
+            _ => continue,
+        };
+        filter.value_set = Some(token_set(tokens));
     }
+    Ok(query)
+}
 
-    let mut rows: Vec<Vec<String>> = Vec::new();
-    for result in reader.records() {
-        let record = result?;
-        let row: Vec<String> = record.iter().map(|field| field.to_string()).collect();
-        rows.push(row);
+// Following only replays the filters and `show` on the appended rows, so
+// stages that need the whole table, or change its columns, can't be used.
+fn follow_conflict(query: &Query) -> Option<&'static str> {
+    let filters = query
+        .filters
+        .iter()
+        .chain(query.or_filters.iter().flatten().flatten());
+    let stages = [
+        (query.group_column.is_some(), "group"),
+        (query.aggregate.is_some(), "aggregates"),
+        (query.minmax_column.is_some(), "minmax"),
+        (!query.joins.is_empty(), "join"),
+        (!query.sort_keys.is_empty(), "sort"),
+        (query.limit.is_some(), "take"),
+        (query.distinct, "distinct"),
+        (query.cumsum.is_some(), "cumsum"),
+        (query.latest_per.is_some(), "latest-per"),
+        (query.list_columns, "columns"),
+        (query.split_by.is_some(), "split-by"),
+        (
+            filters.clone().any(|f| f.quantile.is_some()),
+            "above/below pNN filters",
+        ),
+    ];
+    stages
+        .into_iter()
+        .find_map(|(used, stage)| used.then_some(stage))
+}
+
+// JSON carries the total in its document; YAML and SQL get it as a comment so
+// the output still parses. CSV and Arrow have no room for one, so it goes to
+// `err` instead.
+fn print_total(
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+    format: &str,
+    total: usize,
+) -> std::io::Result<()> {
+    match format {
+        "json" => Ok(()),
+        "csv" | "arrow" => writeln!(err, "Total: {}", total),
+        "yaml" => writeln!(out, "# Total: {}", total),
+        "sql" => writeln!(out, "-- Total: {}", total),
+        _ => writeln!(out, "Total: {}", total),
     }
+}
 
-    if let Some(filter) = &query.filter {
-        rows = apply_filter(rows, filter, &header_map)?;
+// Warnings from the library go to stderr in yellow, verbose details as they are.
+fn print_notices(notices: &Notices) {
+    for notice in notices.take() {
+        match notice {
+            Notice::Warning(message) => {
+                eprintln!("{}", format!("Warning: {}", message).yellow())
+            }
+            Notice::Info(message) => eprintln!("{}", message),
+        }
     }
+}
 
-    if let Some(sort_col) = &query.sort_column {
-        apply_sort(&mut rows, sort_col, query.sort_desc, &header_map)?;
+// Polls the table for appended rows, starting at byte `offset`, and prints the
+// ones matching the query's filter. Runs until the process is interrupted.
+fn follow_table(
+    out: &mut dyn Write,
+    query: &Query,
+    mut offset: u64,
+    col_widths: &[usize],
+    separator: &str,
+) -> Result<(), Box<dyn Error>> {
+    let delimiter = table_delimiter(&query.table_name, query)?;
+    let file_headers = read_header(&query.table_name, query)?;
+    print_notices(&query.notices);
+
+    loop {
+        std::thread::sleep(Duration::from_millis(500));
+
+        let rows = read_appended_rows(query, &mut offset, &file_headers, delimiter)?;
+        for row in &rows {
+            let line = row
+                .iter()
+                .enumerate()
+                .map(|(i, cell)| pad_cell(cell, col_widths.get(i).copied().unwrap_or(0)))
+                .collect::<Vec<_>>()
+                .join(separator);
+            writeln!(out, "{}", line.trim_end())?;
+        }
+        out.flush()?;
     }
+}
 
-    if let Some(limit) = query.limit {
-        rows.truncate(limit);
+// The matching rows appended to the table since byte `offset`, which is moved
+// past them.
+fn read_appended_rows(
+    query: &Query,
+    offset: &mut u64,
+    file_headers: &[String],
+    delimiter: u8,
+) -> Result<Vec<Vec<String>>, Box<dyn Error>> {
+    let len = std::fs::metadata(&query.table_name)?.len();
+    if len < *offset {
+        // The file was truncated or replaced; carry on from its new end.
+        *offset = len;
     }
-    let final_rows = if let Some(columns) = &query.columns {
-        select_columns(rows, columns, &header_names, &header_map)?
-    } else {
-        let mut result = vec![header_names];
-        result.extend(rows);
-        result
+    if len == *offset {
+        return Ok(Vec::new());
+    }
+
+    let mut file = std::fs::File::open(&query.table_name)?;
+    file.seek(SeekFrom::Start(*offset))?;
+    let mut appended = Vec::new();
+    file.take(len - *offset).read_to_end(&mut appended)?;
+
+    // Leave a half-written last line for the next poll.
+    let Some(last_newline) = appended.iter().rposition(|&b| b == b'\n') else {
+        return Ok(Vec::new());
     };
+    appended.truncate(last_newline + 1);
+    *offset += appended.len() as u64;
+
+    let mut appended_reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .from_reader(appended.as_slice());
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for result in appended_reader.records() {
+        let record = result?;
+        rows.push(record.iter().map(|field| field.to_string()).collect());
+    }
+
+    Ok(filter_appended_rows(query, file_headers, rows)?)
+}
+
+// Writes the rows into CSV files under `out_dir`, named by filling the
+// `{column}` placeholders of `template` with each row's values, and returns a
+// summary table of the files written.
+fn write_split_files(
+    results: Vec<Vec<String>>,
+    template: &str,
+    out_dir: &str,
+) -> Result<Vec<Vec<String>>, Box<dyn Error>> {
+    let mut rows = results.into_iter();
+    let header = rows.next().unwrap_or_default();
+    let header_map = build_header_map(&header);
+
+    // Rows are grouped by their file name, so two values that sanitize to the
+    // same name end up in the same file instead of overwriting each other.
+    let mut file_names: Vec<String> = Vec::new();
+    let mut groups: HashMap<String, Vec<Vec<String>>> = HashMap::new();
+    for row in rows {
+        let file_name = render_name_template(template, &row, &header_map)?;
+        if !groups.contains_key(&file_name) {
+            file_names.push(file_name.clone());
+        }
+        groups.entry(file_name).or_default().push(row);
+    }
 
-    Ok(final_rows)
+    let mut summary = vec![vec!["file".to_string(), "rows".to_string()]];
+    for file_name in file_names {
+        let path = std::path::Path::new(out_dir).join(&file_name);
+        let group = &groups[&file_name];
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut writer = csv::Writer::from_path(&path)?;
+        writer.write_record(&header)?;
+        for row in group {
+            writer.write_record(row)?;
+        }
+        writer.flush()?;
+
+        summary.push(vec![path.display().to_string(), group.len().to_string()]);
+    }
+
+    Ok(summary)
 }
 
-fn apply_filter(
-    rows: Vec<Vec<String>>,
-    filter: &Filter,
+// Substitutes every `{column}` in the template with the row's sanitized value
+// for that column. The rest of the template is used as written.
+fn render_name_template(
+    template: &str,
+    row: &[String],
     header_map: &HashMap<String, usize>,
-) -> Result<Vec<Vec<String>>, String> {
-    let column_index = header_map
-        .get(&filter.column)
-        .ok_or_else(|| format!("Column '{}' not found", filter.column))?;
+) -> Result<String, String> {
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| format!("Unclosed placeholder in name template: {}", template))?;
+        let column = &rest[start + 1..end];
+        let index = header_map
+            .get(column)
+            .ok_or_else(|| format!("Column '{}' not found", column))?;
 
-    let filtered_rows: Vec<Vec<String>> = rows
-        .into_iter()
-        .filter(|row| {
-            if let Some(cell_value) = row.get(*column_index) {
-                check_condition(cell_value, &filter.operator, &filter.value)
+        name.push_str(&rest[..start]);
+        name.push_str(&sanitize_file_name(
+            row.get(*index).map(String::as_str).unwrap_or(""),
+        ));
+        rest = &rest[end + 1..];
+    }
+    name.push_str(rest);
+
+    Ok(name)
+}
+
+fn sanitize_file_name(value: &str) -> String {
+    let name: String = value
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
             } else {
-                false
+                '_'
             }
         })
         .collect();
 
-    Ok(filtered_rows)
+    if name.is_empty() {
+        "_empty".to_string()
+    } else {
+        name
+    }
+}
+
+fn print_results(
+    out: &mut dyn Write,
+    results: Vec<Vec<String>>,
+    options: &TableOptions,
+) -> std::io::Result<()> {
+    if results.len() <= 1 && options.interactive {
+        writeln!(out, "{}", "No results found.".yellow())?;
+        return Ok(());
+    }
+    if results.is_empty() {
+        return Ok(());
+    }
+
+    let color_depth = options.color_depth;
+    let highlight = options.highlight;
+    let mut col_widths = column_widths(&results, options.width_sample);
+    if let Some(types) = &options.column_types {
+        fit_widths(&mut col_widths, types);
+    }
+    let highlight_indices: Vec<usize> = highlight
+        .map(|filter| {
+            results[0]
+                .iter()
+                .enumerate()
+                .filter(|(_, h)| filter.columns.contains(h))
+                .map(|(i, _)| i)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // Print table
+    for (row_index, row) in results.iter().enumerate() {
+        let row_str = format_row(row, &col_widths);
+
+        if row_index == 0 {
+            if options.skip_header {
+                continue;
+            }
+            writeln!(out, "{}", accent(&row_str, color_depth, true))?;
+            if let Some(types) = &options.column_types {
+                writeln!(out, "{}", format_row(types, &col_widths).dimmed())?;
+            }
+
+            let separator: String = col_widths
+                .iter()
+                .map(|w| "-".repeat(*w))
+                .collect::<Vec<_>>()
+                .join("-|-");
+            writeln!(out, "{}", accent(&separator, color_depth, false))?;
+        } else if let (false, Some(filter)) = (highlight_indices.is_empty(), highlight) {
+            // Highlight after padding so the escape codes don't throw off the
+            // column widths.
+            let row_str = row
+                .iter()
+                .enumerate()
+                .map(|(i, cell)| {
+                    let width = col_widths.get(i).copied().unwrap_or(0);
+                    if highlight_indices.contains(&i) {
+                        let cell = display_cell(cell);
+                        let padding = " ".repeat(width.saturating_sub(cell.width()));
+                        highlight_matches(&cell, filter) + &padding
+                    } else {
+                        pad_cell(cell, width)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" | ");
+            writeln!(out, "{}", row_str)?;
+        } else {
+            writeln!(out, "{}", row_str)?;
+        }
+    }
+
+    writeln!(
+        out,
+        "\n{}",
+        format!("({} rows)", results.len() - 1).dimmed()
+    )
 }
 
-fn check_condition(cell_value: &str, operator: &str, filter_value: &str) -> bool {
-    match operator {
-        "equals" | "=" | "==" => cell_value.to_lowercase() == filter_value.to_lowercase(),
-        "greater" | ">" => match (cell_value.parse::<f64>(), filter_value.parse::<f64>()) {
-            (Ok(a), Ok(b)) => a > b,
-            _ => cell_value > filter_value,
-        },
-        "less" | "<" => match (cell_value.parse::<f64>(), filter_value.parse::<f64>()) {
-            (Ok(a), Ok(b)) => a < b,
-            _ => cell_value < filter_value,
-        },
-        "contains" => cell_value
-            .to_lowercase()
-            .contains(&filter_value.to_lowercase()),
-        _ => false,
+// The table's accent color (cyan) at the requested color depth. Deeper
+// palettes get a softer shade; 16 colors keep the basic ANSI cyan.
+fn accent(text: &str, color_depth: &str, bold: bool) -> String {
+    if !colored::control::SHOULD_COLORIZE.should_colorize() {
+        return text.to_string();
+    }
+
+    match color_depth {
+        // Written by hand: colored falls back to basic ANSI for truecolor
+        // unless COLORTERM says otherwise, and has no 256-color palette.
+        "truecolor" => format!(
+            "\x1b[{}38;2;0;175;215m{}\x1b[0m",
+            if bold { "1;" } else { "" },
+            text
+        ),
+        "256" => format!(
+            "\x1b[{}38;5;38m{}\x1b[0m",
+            if bold { "1;" } else { "" },
+            text
+        ),
+        _ => {
+            let colored = text.cyan();
+            if bold { colored.bold() } else { colored }.to_string()
+        }
     }
 }
 
-fn apply_sort(
-    rows: &mut Vec<Vec<String>>,
-    sort_column: &str,
-    descending: bool,
-    header_map: &HashMap<String, usize>,
-) -> Result<(), String> {
-    let column_index = header_map
-        .get(sort_column)
-        .ok_or_else(|| format!("Column '{}' not found", sort_column))?;
+// Passes output through until the byte limit is reached. Output is cut at a
+// line boundary: the first line that would go over the limit and everything
+// after it are dropped, and `finish` reports the truncation on stderr.
+struct CappedWriter<W: Write> {
+    inner: W,
+    limit: Option<usize>,
+    written: usize,
+    line: Vec<u8>,
+    truncated: bool,
+}
 
-    let empty_string = String::new();
+impl<W: Write> CappedWriter<W> {
+    fn new(inner: W, limit: Option<usize>) -> Self {
+        CappedWriter {
+            inner,
+            limit,
+            written: 0,
+            line: Vec::new(),
+            truncated: false,
+        }
+    }
 
-    rows.sort_by(|a, b| {
-        let val_a = a.get(*column_index).unwrap_or(&empty_string);
-        let val_b = b.get(*column_index).unwrap_or(&empty_string);
+    fn emit_line(&mut self) -> std::io::Result<()> {
+        if !self.truncated
+            && self
+                .limit
+                .is_some_and(|limit| self.written + self.line.len() > limit)
+        {
+            self.truncated = true;
+        }
+        if !self.truncated {
+            self.inner.write_all(&self.line)?;
+            self.written += self.line.len();
+        }
+        self.line.clear();
+        Ok(())
+    }
 
-        let comparison = match (val_a.parse::<f64>(), val_b.parse::<f64>()) {
-            (Ok(num_a), Ok(num_b)) => num_a.partial_cmp(&num_b).unwrap(),
-            _ => val_a.cmp(val_b),
-        };
+    fn finish(mut self) -> std::io::Result<()> {
+        self.emit_line()?;
+        self.inner.flush()?;
+        if self.truncated {
+            eprintln!(
+                "{}",
+                format!(
+                    "... output truncated at {} bytes (--max-output-bytes)",
+                    self.written
+                )
+                .yellow()
+            );
+        }
+        Ok(())
+    }
+}
 
-        if descending {
-            comparison.reverse()
-        } else {
-            comparison
+impl<W: Write> Write for CappedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for line in buf.split_inclusive(|&b| b == b'\n') {
+            self.line.extend_from_slice(line);
+            if line.ends_with(b"\n") {
+                self.emit_line()?;
+            }
         }
-    });
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
 
+// Writes to a temporary file next to `path` and renames it over, so a monitor
+// reading `path` never sees a partly written count.
+fn write_count_file(path: &str, count: usize) -> std::io::Result<()> {
+    let tmp_path = format!("{}.tmp{}", path, std::process::id());
+    std::fs::write(&tmp_path, format!("{}\n", count))?;
+    if let Err(e) = std::fs::rename(&tmp_path, path) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
     Ok(())
 }
 
-fn select_columns(
-    rows: Vec<Vec<String>>,
-    columns: &[String],
-    _header_names: &[String],
-    header_map: &HashMap<String, usize>,
-) -> Result<Vec<Vec<String>>, String> {
-    let mut column_indices = Vec::new();
-    for col in columns {
-        let index = header_map
-            .get(col)
-            .ok_or_else(|| format!("Column '{}' not found", col))?;
-        column_indices.push(*index);
+// `--show-types`: the inferred type of each column, from its non-blank cells.
+fn column_types(results: &[Vec<String>]) -> Vec<String> {
+    let header_len = results.first().map_or(0, |header| header.len());
+    (0..header_len)
+        .map(|i| {
+            let values: Vec<&str> = results
+                .iter()
+                .skip(1)
+                .filter_map(|row| row.get(i))
+                .map(|v| v.as_str())
+                .filter(|v| !v.trim().is_empty())
+                .collect();
+            infer_column_type(&values).to_string()
+        })
+        .collect()
+}
+
+// `--delta-columns`: clears each cell equal to the one above it, leaving the
+// header alone.
+fn blank_repeated_cells(results: &mut [Vec<String>]) {
+    // Bottom-up, so each row is compared with the unblanked row above.
+    for i in (2..results.len()).rev() {
+        let (above, below) = results.split_at_mut(i);
+        let above = &above[i - 1];
+        for (cell, previous) in below[0].iter_mut().zip(above) {
+            if cell == previous {
+                cell.clear();
+            }
+        }
     }
+}
 
-    let mut result = Vec::new();
+// `--index`: a leading `#` column numbering the rows from 1.
+fn add_index_column(results: &mut [Vec<String>]) {
+    for (number, row) in results.iter_mut().enumerate() {
+        let label = if number == 0 {
+            "#".to_string()
+        } else {
+            number.to_string()
+        };
+        row.insert(0, label);
+    }
+}
 
-    result.push(columns.to_vec());
-    for row in rows {
-        let selected_row: Vec<String> = column_indices
-            .iter()
-            .map(|&i| row.get(i).unwrap_or(&String::new()).to_string())
-            .collect();
-        result.push(selected_row);
+// 64-bit FNV-1a over the header and rows. Fields and rows are terminated by
+// separator bytes, so moving text between cells changes the checksum.
+fn result_checksum(results: &[Vec<String>]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |bytes: &[u8]| {
+        for &byte in bytes {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+    for row in results {
+        for cell in row {
+            feed(cell.as_bytes());
+            feed(&[0x1f]);
+        }
+        feed(&[0x1e]);
     }
+    hash
+}
 
-    Ok(result)
+// The encoding named by `--output-encoding`. encoding_rs writes UTF-8 for
+// labels it can only decode, such as utf-16, so those are refused rather than
+// silently ignored. Arrow output is binary and can't be re-encoded.
+fn output_encoding(label: &str, format: &str) -> Result<&'static encoding_rs::Encoding, String> {
+    let encoding = encoding_rs::Encoding::for_label(label.as_bytes())
+        .ok_or_else(|| format!("Unknown output encoding '{}'", label))?;
+    if encoding.output_encoding() != encoding {
+        return Err(format!("Output encoding '{}' can't be written", label));
+    }
+    if format == "arrow" && encoding != encoding_rs::UTF_8 {
+        return Err("--format arrow can't be used with --output-encoding".to_string());
+    }
+    Ok(encoding)
 }
 
-fn print_results(results: Vec<Vec<String>>) {
-    if results.is_empty() {
-        println!("{}", "No results found.".yellow());
-        return;
+// Re-encodes the UTF-8 written to it. Characters the target encoding has no
+// code for are written as `?`.
+struct TranscodingWriter<W: Write> {
+    inner: W,
+    encoder: encoding_rs::Encoder,
+    // The start of a character split across two writes.
+    pending: Vec<u8>,
+}
+
+impl<W: Write> TranscodingWriter<W> {
+    fn new(inner: W, encoding: &'static encoding_rs::Encoding) -> Self {
+        TranscodingWriter {
+            inner,
+            encoder: encoding.new_encoder(),
+            pending: Vec::new(),
+        }
     }
+}
 
-    let mut col_widths = vec![0; results[0].len()];
-    for row in &results {
-        for (i, cell) in row.iter().enumerate() {
-            if cell.len() > col_widths[i] {
-                col_widths[i] = cell.len();
+impl<W: Write> Write for TranscodingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        };
+        let text = String::from_utf8(self.pending.drain(..valid).collect())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let mut input = text.as_str();
+        let mut output = vec![0; input.len() * 2 + 16];
+        loop {
+            let (result, read, written) =
+                self.encoder
+                    .encode_from_utf8_without_replacement(input, &mut output, false);
+            self.inner.write_all(&output[..written])?;
+            input = &input[read..];
+            match result {
+                encoding_rs::EncoderResult::InputEmpty => break,
+                encoding_rs::EncoderResult::OutputFull => {}
+                encoding_rs::EncoderResult::Unmappable(_) => self.inner.write_all(b"?")?,
             }
         }
+        Ok(buf.len())
     }
 
-    // Print table
-    for (row_index, row) in results.iter().enumerate() {
-        let row_str: String = row
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Space-aligned columns for tools that split on whitespace: no borders, no
+// separator row and no color.
+fn print_plain(
+    out: &mut dyn Write,
+    results: &[Vec<String>],
+    skip_header: bool,
+) -> std::io::Result<()> {
+    let col_widths = column_widths(results, None);
+    for row in results.iter().skip(usize::from(skip_header)) {
+        let line = row
             .iter()
             .enumerate()
-            .map(|(i, cell)| format!("{:<width$}", cell, width = col_widths[i]))
+            .map(|(i, cell)| pad_cell(cell, col_widths.get(i).copied().unwrap_or(0)))
             .collect::<Vec<_>>()
-            .join(" | ");
+            .join("  ");
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
 
-        if row_index == 0 {
-            println!("{}", row_str.cyan().bold());
+// One `col=value` line per row, for grep and other line-oriented tools.
+// Keys and values with spaces, quotes or `=` are double quoted, as are empty
+// values.
+fn print_kv(out: &mut dyn Write, results: &[Vec<String>]) -> std::io::Result<()> {
+    let Some((header, rows)) = results.split_first() else {
+        return Ok(());
+    };
+    for row in rows {
+        let line = header
+            .iter()
+            .zip(row)
+            .map(|(name, cell)| format!("{}={}", kv_quote(name), kv_quote(cell)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
 
-            let separator: String = col_widths
+fn kv_quote(text: &str) -> String {
+    if !text.is_empty() && !text.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        return text.to_string();
+    }
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// A sequence of mappings keyed by header name, in column order. Appending
+// (`skip_header`) just adds more sequence items.
+fn print_yaml(
+    out: &mut dyn Write,
+    results: &[Vec<String>],
+    skip_header: bool,
+) -> std::io::Result<()> {
+    let Some((header, rows)) = results.split_first() else {
+        return Ok(());
+    };
+    if rows.is_empty() {
+        if !skip_header {
+            writeln!(out, "[]")?;
+        }
+        return Ok(());
+    }
+
+    let records: Vec<serde_yaml::Mapping> = rows
+        .iter()
+        .map(|row| {
+            header
                 .iter()
-                .map(|w| "-".repeat(*w))
-                .collect::<Vec<_>>()
-                .join("-|-");
-            println!("{}", separator.cyan());
+                .zip(row)
+                .map(|(name, cell)| {
+                    let value = match infer_value(cell) {
+                        InferredValue::Integer(n) => serde_yaml::Value::from(n),
+                        InferredValue::Float(n) => serde_yaml::Value::from(n),
+                        InferredValue::Bool(b) => serde_yaml::Value::from(b),
+                        InferredValue::Text(text) => serde_yaml::Value::from(text),
+                    };
+                    (serde_yaml::Value::from(name.as_str()), value)
+                })
+                .collect()
+        })
+        .collect();
+
+    let yaml = serde_yaml::to_string(&records).map_err(std::io::Error::other)?;
+    write!(out, "{}", yaml)
+}
+
+// Quoted where needed, so the output reads back as the same table. Uses the
+// input delimiter, or a comma when that is detected per file.
+fn print_csv(
+    out: &mut dyn Write,
+    results: &[Vec<String>],
+    skip_header: bool,
+    delimiter: u8,
+) -> std::io::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(out);
+    for row in results.iter().skip(usize::from(skip_header)) {
+        writer.write_record(row)?;
+    }
+    writer.flush()
+}
+
+// `[{"col": v, ...}, ...]`, or with `orient` "columns" `{"col": [v, ...], ...}`.
+// With a `total`, the rows go under "rows" followed by a "meta" object
+// holding the total.
+fn print_json(
+    out: &mut dyn Write,
+    results: &[Vec<String>],
+    orient: &str,
+    total: Option<usize>,
+) -> std::io::Result<()> {
+    let (header, rows) = results
+        .split_first()
+        .map_or((&[][..], &[][..]), |(header, rows)| {
+            (header.as_slice(), rows)
+        });
+
+    let document = if orient == "columns" {
+        let columns: serde_json::Map<String, serde_json::Value> = header
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let values = rows
+                    .iter()
+                    .map(|row| {
+                        row.get(i)
+                            .map_or(serde_json::Value::Null, |c| json_value(c))
+                    })
+                    .collect();
+                (name.clone(), serde_json::Value::Array(values))
+            })
+            .collect();
+        serde_json::Value::Object(columns)
+    } else {
+        let records = rows
+            .iter()
+            .map(|row| {
+                let record: serde_json::Map<String, serde_json::Value> = header
+                    .iter()
+                    .zip(row)
+                    .map(|(name, cell)| (name.clone(), json_value(cell)))
+                    .collect();
+                serde_json::Value::Object(record)
+            })
+            .collect();
+        serde_json::Value::Array(records)
+    };
+    let document = match total {
+        Some(total) => serde_json::json!({
+            "rows": document,
+            "meta": { "total": total },
+        }),
+        None => document,
+    };
+
+    serde_json::to_writer_pretty(&mut *out, &document)?;
+    writeln!(out)
+}
+
+fn json_value(cell: &str) -> serde_json::Value {
+    match infer_value(cell) {
+        InferredValue::Integer(n) => serde_json::Value::from(n),
+        InferredValue::Float(n) => serde_json::Value::from(n),
+        InferredValue::Bool(b) => serde_json::Value::from(b),
+        InferredValue::Text(text) => serde_json::Value::from(text),
+    }
+}
+
+// Writes the results as an Arrow IPC file. A column whose non-empty cells are
+// all integers becomes Int64, all numbers Float64, anything else Utf8; empty
+// cells are nulls.
+#[cfg(feature = "arrow")]
+fn print_arrow(out: &mut dyn Write, results: &[Vec<String>]) -> std::io::Result<()> {
+    use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+    use arrow_schema::{Field, Schema};
+    use std::sync::Arc;
+
+    let Some((header, rows)) = results.split_first() else {
+        return Ok(());
+    };
+
+    let mut fields = Vec::new();
+    let mut columns: Vec<ArrayRef> = Vec::new();
+    for (i, name) in header.iter().enumerate() {
+        let cells: Vec<Option<&str>> = rows
+            .iter()
+            .map(|row| row.get(i).map(|c| c.as_str()).filter(|c| !c.is_empty()))
+            .collect();
+        let values = || cells.iter().flatten().map(|c| infer_value(c));
+        let column: ArrayRef = if values().all(|v| matches!(v, InferredValue::Integer(_))) {
+            Arc::new(Int64Array::from_iter(cells.iter().map(|c| {
+                c.and_then(|c| match infer_value(c) {
+                    InferredValue::Integer(n) => Some(n),
+                    _ => None,
+                })
+            })))
+        } else if values().all(|v| matches!(v, InferredValue::Integer(_) | InferredValue::Float(_)))
+        {
+            Arc::new(Float64Array::from_iter(cells.iter().map(|c| {
+                c.and_then(|c| match infer_value(c) {
+                    InferredValue::Integer(n) => Some(n as f64),
+                    InferredValue::Float(n) => Some(n),
+                    _ => None,
+                })
+            })))
         } else {
-            println!("{}", row_str);
+            Arc::new(StringArray::from_iter(cells.iter().copied()))
+        };
+        fields.push(Field::new(name, column.data_type().clone(), true));
+        columns.push(column);
+    }
+
+    let schema = Arc::new(Schema::new(fields));
+    let batch = RecordBatch::try_new(schema.clone(), columns).map_err(std::io::Error::other)?;
+    let mut writer =
+        arrow_ipc::writer::FileWriter::try_new(out, &schema).map_err(std::io::Error::other)?;
+    writer.write(&batch).map_err(std::io::Error::other)?;
+    writer.finish().map_err(std::io::Error::other)
+}
+
+// `INSERT INTO <table> (<columns>) VALUES (...), (...);` with up to `batch`
+// rows per statement. Numbers are written as literals, everything else as a
+// quoted string.
+fn print_sql(
+    out: &mut dyn Write,
+    results: &[Vec<String>],
+    table_name: &str,
+    batch: usize,
+) -> std::io::Result<()> {
+    let Some((header, rows)) = results.split_first() else {
+        return Ok(());
+    };
+
+    let columns: Vec<String> = header.iter().map(|name| sql_identifier(name)).collect();
+    let insert = format!(
+        "INSERT INTO {} ({}) VALUES",
+        sql_identifier(table_name),
+        columns.join(", ")
+    );
+
+    for chunk in rows.chunks(batch) {
+        let tuples: Vec<String> = chunk
+            .iter()
+            .map(|row| {
+                let values: Vec<String> = row.iter().map(|cell| sql_literal(cell)).collect();
+                format!("({})", values.join(", "))
+            })
+            .collect();
+        writeln!(out, "{} {};", insert, tuples.join(", "))?;
+    }
+    Ok(())
+}
+
+// Plain names are left alone; anything else is double-quoted.
+fn sql_identifier(name: &str) -> String {
+    let plain = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if plain {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+fn sql_literal(cell: &str) -> String {
+    match infer_value(cell) {
+        InferredValue::Integer(_) | InferredValue::Float(_) => cell.to_string(),
+        _ => format!("'{}'", cell.replace('\'', "''")),
+    }
+}
+
+// Wraps what the filter matched in `text` in reverse video: every
+// case-insensitive occurrence of a `contains` value, or every match of a
+// `matches` pattern.
+fn highlight_matches(text: &str, filter: &Filter) -> String {
+    let ranges: Vec<(usize, usize)> = match &filter.regex {
+        Some(regex) => regex
+            .find_iter(text)
+            .filter(|m| !m.is_empty())
+            .map(|m| (m.start(), m.end()))
+            .collect(),
+        None => occurrences(text, &filter.value),
+    };
+
+    let mut result = String::new();
+    let mut last = 0;
+    for (start, end) in ranges {
+        result.push_str(&text[last..start]);
+        result.push_str(&text[start..end].reversed().bold().to_string());
+        last = end;
+    }
+    result.push_str(&text[last..]);
+    result
+}
+
+// Byte ranges of the case-insensitive occurrences of `needle` in `text`.
+fn occurrences(text: &str, needle: &str) -> Vec<(usize, usize)> {
+    let needle: Vec<char> = needle.chars().flat_map(char::to_lowercase).collect();
+    if needle.is_empty() {
+        return Vec::new();
+    }
+
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut ranges = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let candidate: Vec<char> = chars[i..]
+            .iter()
+            .take(needle.len())
+            .flat_map(|(_, c)| c.to_lowercase())
+            .collect();
+        if candidate == needle {
+            let end = chars
+                .get(i + needle.len())
+                .map_or(text.len(), |&(at, _)| at);
+            ranges.push((chars[i].0, end));
+            i += needle.len();
+        } else {
+            i += 1;
+        }
+    }
+    ranges
+}
+
+fn column_widths(results: &[Vec<String>], width_sample: Option<usize>) -> Vec<usize> {
+    // The header always counts towards the widths; with a sample size only the
+    // first K data rows do, and later rows are allowed to overflow.
+    let sampled = width_sample.map_or(results.len(), |k| k.saturating_add(1));
+    let mut col_widths = vec![0; results.first().map_or(0, |header| header.len())];
+    for row in results.iter().take(sampled) {
+        for (i, cell) in row.iter().enumerate() {
+            let width = display_cell(cell).width();
+            if i < col_widths.len() && width > col_widths[i] {
+                col_widths[i] = width;
+            }
         }
     }
+    col_widths
+}
+
+// Widens the columns that `cells` wouldn't fit in.
+fn fit_widths(col_widths: &mut [usize], cells: &[String]) {
+    for (width, cell) in col_widths.iter_mut().zip(cells) {
+        *width = (*width).max(display_cell(cell).width());
+    }
+}
+
+fn format_row(row: &[String], col_widths: &[usize]) -> String {
+    row.iter()
+        .enumerate()
+        .map(|(i, cell)| pad_cell(cell, col_widths.get(i).copied().unwrap_or(0)))
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+// Pads to `width` terminal columns; `{:<width$}` would count chars, which is
+// off for wide characters such as CJK.
+fn pad_cell(cell: &str, width: usize) -> String {
+    let cell = display_cell(cell);
+    let padding = width.saturating_sub(cell.width());
+    format!("{}{}", cell, " ".repeat(padding))
+}
 
-    println!("\n{}", format!("({} rows)", results.len() - 1).dimmed());
+// Line breaks inside a (quoted) cell or header would break the table layout,
+// so they are shown escaped.
+fn display_cell(cell: &str) -> std::borrow::Cow<'_, str> {
+    if cell.contains(['\n', '\r']) {
+        cell.replace('\r', "\\r").replace('\n', "\\n").into()
+    } else {
+        cell.into()
+    }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter()
+            .map(|row| row.iter().map(|cell| cell.to_string()).collect())
+            .collect()
+    }
+
+    // A fresh, empty directory for one test's files.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("flexiql-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("flexiql").chain(args.iter().copied())).unwrap()
+    }
+
+    // What a printer writes, as text.
+    fn rendered(print: impl FnOnce(&mut dyn Write) -> std::io::Result<()>) -> String {
+        let mut out = Vec::new();
+        print(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_width_sample() {
+        let results = grid(&[&["id", "name"], &["1", "Ann"], &["2", "Bartholomew"]]);
+        assert_eq!(column_widths(&results, Some(1)), vec![2, 4]);
+        assert_eq!(column_widths(&results, None), vec![2, 11]);
+    }
+
+    #[test]
+    fn test_split_files() {
+        let dir = temp_dir("split");
+        let results = grid(&[
+            &["region", "amount"],
+            &["north", "10"],
+            &["South East", "20"],
+            &["north", "30"],
+        ]);
+        let summary = write_split_files(results, "{region}.csv", dir.to_str().unwrap()).unwrap();
+        assert_eq!(summary.len(), 3);
+
+        let north = std::fs::read_to_string(dir.join("north.csv")).unwrap();
+        assert_eq!(north, "region,amount\nnorth,10\nnorth,30\n");
+        let south = std::fs::read_to_string(dir.join("South_East.csv")).unwrap();
+        assert_eq!(south, "region,amount\nSouth East,20\n");
+    }
+
+    #[test]
+    fn test_follow_reads_appended_matches() {
+        let path = temp_dir("follow").join("log.csv");
+        std::fs::write(&path, "level,message\ninfo,started\n").unwrap();
+        let input = format!("{} >> level equals error >> show message", path.display());
+        let query = prepare_query(&input, &cli(&[&input])).unwrap();
+        let file_headers = read_header(&query.table_name, &query).unwrap();
+        let mut offset = std::fs::metadata(&path).unwrap().len();
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        write!(file, "error,disk full\ninfo,retrying\nerror,half").unwrap();
+        let rows = read_appended_rows(&query, &mut offset, &file_headers, b',').unwrap();
+        assert_eq!(rows, grid(&[&["disk full"]]));
+
+        writeln!(file, " written").unwrap();
+        let rows = read_appended_rows(&query, &mut offset, &file_headers, b',').unwrap();
+        assert_eq!(rows, grid(&[&["half written"]]));
+        assert_eq!(offset, std::fs::metadata(&path).unwrap().len());
+    }
+
+    #[test]
+    fn test_follow_conflicts() {
+        let conflict =
+            |input: &str| follow_conflict(&prepare_query(input, &cli(&[input])).unwrap());
+        assert_eq!(conflict("logs >> level equals error >> show message"), None);
+        assert_eq!(conflict("logs >> count"), Some("aggregates"));
+        assert_eq!(conflict("logs >> sort level"), Some("sort"));
+        assert_eq!(
+            conflict("logs >> amount above p90"),
+            Some("above/below pNN filters")
+        );
+    }
+
+    #[test]
+    fn test_highlight_matches() {
+        colored::control::set_override(true);
+        let query = prepare_query("t >> name contains ann", &cli(&["t"])).unwrap();
+        assert_eq!(
+            highlight_matches("Joanna", &query.filters[0]),
+            "Jo\u{1b}[1;7mann\u{1b}[0ma"
+        );
+
+        let query = prepare_query("t >> code matches [0-9]+", &cli(&["t"])).unwrap();
+        assert_eq!(
+            highlight_matches("ab12cd3", &query.filters[0]),
+            "ab\u{1b}[1;7m12\u{1b}[0mcd\u{1b}[1;7m3\u{1b}[0m"
+        );
+    }
+
+    #[test]
+    fn test_default_table_from_env() {
+        // clap reads AQL_TABLE into `--table`; setting the variable here would
+        // race the other tests parsing their arguments.
+        let command = <Cli as clap::CommandFactory>::command();
+        let table = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == "table")
+            .unwrap();
+        assert_eq!(table.get_env(), Some(std::ffi::OsStr::new("AQL_TABLE")));
+
+        let args = cli(&["--table", "staff", ">> show name"]);
+        let query = prepare_query(">> show name", &args).unwrap();
+        assert_eq!(query.table_name, "staff.csv");
+    }
+
+    #[test]
+    fn test_name_template() {
+        let dir = temp_dir("template");
+        let results = grid(&[
+            &["region", "year", "amount"],
+            &["north", "2023", "10"],
+            &["north", "2024", "20"],
+            &["west/east", "2024", "30"],
+        ]);
+        let summary =
+            write_split_files(results, "{region}_{year}.csv", dir.to_str().unwrap()).unwrap();
+        let files: Vec<String> = summary[1..]
+            .iter()
+            .map(|row| {
+                let path = std::path::Path::new(&row[0]);
+                path.file_name().unwrap().to_string_lossy().into_owned()
+            })
+            .collect();
+        assert_eq!(
+            files,
+            vec!["north_2023.csv", "north_2024.csv", "west_east_2024.csv"]
+        );
+        assert!(dir.join("west_east_2024.csv").exists());
+    }
+
+    #[test]
+    fn test_plain_output() {
+        let results = grid(&[
+            &["id", "name", "city"],
+            &["1", "Ann", "Oslo"],
+            &["22", "Bartholomew", ""],
+        ]);
+        let output = rendered(|out| print_plain(out, &results, false));
+        assert_eq!(
+            output,
+            "id  name         city\n1   Ann          Oslo\n22  Bartholomew\n"
+        );
+        assert!(!output.contains('|'));
+    }
+
+    #[test]
+    fn test_output_byte_cap() {
+        let mut sink = Vec::new();
+        let mut out = CappedWriter::new(&mut sink, Some(10));
+        write!(out, "id\n1\n22\n333\n4444\n").unwrap();
+        assert!(out.truncated);
+        out.finish().unwrap();
+        // Whole lines only: the next one would have crossed the cap.
+        assert_eq!(String::from_utf8(sink).unwrap(), "id\n1\n22\n");
+
+        let mut sink = Vec::new();
+        let mut out = CappedWriter::new(&mut sink, None);
+        write!(out, "id\n1\n22\n333\n4444\n").unwrap();
+        assert!(!out.truncated);
+        out.finish().unwrap();
+        assert_eq!(sink.len(), 17);
+    }
+
+    #[test]
+    fn test_color_depth() {
+        colored::control::set_override(true);
+        let table = |color_depth: &str| {
+            let options = TableOptions {
+                width_sample: None,
+                highlight: None,
+                color_depth,
+                skip_header: false,
+                interactive: false,
+                column_types: None,
+            };
+            rendered(|out| print_results(out, grid(&[&["id"], &["1"]]), &options))
+        };
+        assert!(table("truecolor").contains("\x1b[1;38;2;"));
+        assert!(table("256").contains("\x1b[1;38;5;"));
+        let basic = table("16");
+        assert!(basic.contains("\x1b[1;36m"));
+        assert!(!basic.contains("38;2;") && !basic.contains("38;5;"));
+    }
+
+    #[test]
+    fn test_header_with_comma() {
+        let csv = "\"last, first\",age\n\"Lee, Ann\",30\n";
+        let results = run_query(r#"people >> show "last, first", age"#, csv.as_bytes()).unwrap();
+        assert_eq!(
+            results,
+            grid(&[&["last, first", "age"], &["Lee, Ann", "30"]])
+        );
+
+        let output = rendered(|out| print_csv(out, &results, false, b','));
+        assert_eq!(output, csv);
+        let output = rendered(|out| print_json(out, &results, "rows", None));
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([{"last, first": "Lee, Ann", "age": 30}])
+        );
+    }
+
+    #[test]
+    fn test_append_output() {
+        let path = temp_dir("append").join("combined.csv");
+        let path = path.to_str().unwrap();
+        for rows in [grid(&[&["id"], &["1"]]), grid(&[&["id"], &["2"], &["3"]])] {
+            let (mut file, skip_header) = open_output(path, true).unwrap();
+            print_csv(&mut file, &rows, skip_header, b',').unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "id\n1\n2\n3\n");
+
+        let (mut file, skip_header) = open_output(path, false).unwrap();
+        print_csv(&mut file, &grid(&[&["id"], &["4"]]), skip_header, b',').unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "id\n4\n");
+    }
+
+    #[test]
+    fn test_yaml_round_trip() {
+        let results = grid(&[
+            &["id", "name", "code", "active"],
+            &["1", "Ann: admin", "007", "true"],
+            &["2", "Bob", "1.5", "false"],
+        ]);
+        let output = rendered(|out| print_yaml(out, &results, false));
+        let records: Vec<serde_yaml::Mapping> = serde_yaml::from_str(&output).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["id"], serde_yaml::Value::from(1));
+        assert_eq!(records[0]["name"], serde_yaml::Value::from("Ann: admin"));
+        assert_eq!(records[0]["code"], serde_yaml::Value::from("007"));
+        assert_eq!(records[0]["active"], serde_yaml::Value::from(true));
+        assert_eq!(records[1]["code"], serde_yaml::Value::from(1.5));
+    }
+
+    #[test]
+    fn test_count_file() {
+        let dir = temp_dir("count");
+        let path = dir.join("count.txt");
+        write_count_file(path.to_str().unwrap(), 42).unwrap();
+        write_count_file(path.to_str().unwrap(), 7).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "7\n");
+        // The temporary file is renamed away.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_custom_boolean_tokens() {
+        let args = cli(&["--true-values", "ja, j", "--false-values", "nein", "t"]);
+        let csv = "name,active\nAnn,Ja\nBob,nein\nCal,yes\nDee,j\n";
+        let (header_names, rows) = read_csv(csv.as_bytes(), b',').unwrap();
+
+        let query = prepare_query("t >> active is true >> show name", &args).unwrap();
+        let results = execute_query_on(&query, header_names.clone(), rows.clone()).unwrap();
+        assert_eq!(results, grid(&[&["name"], &["Ann"], &["Dee"]]));
+
+        // `yes` is in neither set, so it is neither true nor false.
+        let query = prepare_query("t >> active is false >> show name", &args).unwrap();
+        let results = execute_query_on(&query, header_names, rows).unwrap();
+        assert_eq!(results, grid(&[&["name"], &["Bob"]]));
+    }
+
+    #[test]
+    fn test_sql_inserts() {
+        let results = grid(&[
+            &["id", "name"],
+            &["1", "O'Brien"],
+            &["2", "007"],
+            &["3", "Ann"],
+        ]);
+        let output = rendered(|out| print_sql(out, &results, "employees", 2));
+        assert_eq!(
+            output,
+            "INSERT INTO employees (id, name) VALUES (1, 'O''Brien'), (2, '007');\n\
+             INSERT INTO employees (id, name) VALUES (3, 'Ann');\n"
+        );
+    }
+
+    #[test]
+    fn test_max_lines() {
+        let mut results: Vec<Vec<String>> = std::iter::once(vec!["id".to_string()])
+            .chain((1..=100).map(|i| vec![i.to_string()]))
+            .collect();
+        // Ten lines of table: five go to the header, separator, notice and
+        // row count, leaving five rows.
+        assert_eq!(fit_to_lines(&mut results, 10, 5), 95);
+        assert_eq!(results.len(), 6);
+        assert_eq!(results[5], vec!["5"]);
+
+        assert_eq!(fit_to_lines(&mut results, 2, 5), 4);
+        assert_eq!(results.len(), 2);
+        assert_eq!(fit_to_lines(&mut results, 50, 5), 0);
+    }
+
+    #[test]
+    fn test_json_columns_orient() {
+        let results = grid(&[&["id", "name"], &["1", "Ann"], &["2", "007"], &["3", ""]]);
+        let output = rendered(|out| print_json(out, &results, "columns", None));
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"id": [1, 2, 3], "name": ["Ann", "007", ""]})
+        );
+    }
+
+    #[test]
+    fn test_output_encoding() {
+        let encoding = output_encoding("latin1", "csv").unwrap();
+        let mut sink = Vec::new();
+        let mut out = TranscodingWriter::new(&mut sink, encoding);
+        // A character split across two writes is still encoded whole.
+        out.write_all("name\nJos\u{e9} ".as_bytes()).unwrap();
+        out.write_all(&"\u{e9}\u{20ac}\n".as_bytes()[..1]).unwrap();
+        out.write_all(&"\u{e9}\u{20ac}\n".as_bytes()[1..]).unwrap();
+        assert_eq!(sink, b"name\nJos\xe9 \xe9\x80\n");
+        let (decoded, _, had_errors) = encoding.decode(&sink);
+        assert!(!had_errors);
+        assert_eq!(decoded, "name\nJos\u{e9} \u{e9}\u{20ac}\n");
+
+        assert!(output_encoding("utf-16", "csv").is_err());
+        assert!(output_encoding("replacement", "csv").is_err());
+        assert!(output_encoding("latin1", "arrow").is_err());
+        assert!(output_encoding("utf-8", "arrow").is_ok());
+    }
+
+    #[test]
+    fn test_eu_locale() {
+        let args = cli(&["--locale", "eu", "t"]);
+        let csv = "name;price\nTea;3,14\nJam;12,5\nCake;1.234,50\n";
+        let (header_names, rows) = read_csv(csv.as_bytes(), b';').unwrap();
+
+        let query = prepare_query("t >> price greater than 3,2 >> sort price", &args).unwrap();
+        assert_eq!(query.delimiter, Some(b';'));
+        let results = execute_query_on(&query, header_names.clone(), rows.clone()).unwrap();
+        assert_eq!(
+            results,
+            grid(&[&["name", "price"], &["Jam", "12,5"], &["Cake", "1.234,50"]])
+        );
+
+        let query = prepare_query("t >> sum price", &args).unwrap();
+        let results = execute_query_on(&query, header_names, rows).unwrap();
+        assert_eq!(results[1], vec!["1250.14"]);
+    }
 
-//     #[test]
-//     fn test_parse_simple_query() {
-//         let query = parse_query("employees >> show name").unwrap();
-//         assert_eq!(query.table_name, "employees.csv");
-//         assert!(query.columns.is_some());
-//     }
+    #[test]
+    fn test_with_count_keeps_output_parseable() {
+        let results = grid(&[&["name"], &["Ann"], &["Bo"]]);
+        let mut err = Vec::new();
+        let csv = rendered(|out| {
+            print_csv(out, &results, false, b',')?;
+            print_total(out, &mut err, "csv", 2)
+        });
+        assert_eq!(csv, "name\nAnn\nBo\n");
+        assert_eq!(String::from_utf8(err).unwrap(), "Total: 2\n");
 
-//     #[test]
-//     fn test_parse_filter_query() {
-//         let query =
-//             parse_query("employees >> salary greater than 50000 >> show name, salary").unwrap();
-//         assert!(query.filter.is_some());
-//         assert!(query.columns.is_some());
-//     }
-// }
+        let mut err = Vec::new();
+        let yaml = rendered(|out| print_total(out, &mut err, "yaml", 2));
+        assert_eq!(yaml, "# Total: 2\n");
+        assert!(err.is_empty());
+
+        let json = rendered(|out| print_json(out, &results, "rows", Some(2)));
+        let document: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(document["meta"]["total"], 2);
+    }
+
+    #[cfg(feature = "arrow")]
+    #[test]
+    fn test_arrow_read_back() {
+        use arrow_schema::DataType;
+
+        let results = grid(&[
+            &["name", "age", "score"],
+            &["Ann", "31", "4.5"],
+            &["Bo", "", "3"],
+        ]);
+        let mut bytes = Vec::new();
+        print_arrow(&mut bytes, &results).unwrap();
+
+        let reader =
+            arrow_ipc::reader::FileReader::try_new(std::io::Cursor::new(bytes), None).unwrap();
+        let schema = reader.schema();
+        let types: Vec<(&str, &DataType)> = schema
+            .fields()
+            .iter()
+            .map(|field| (field.name().as_str(), field.data_type()))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("name", &DataType::Utf8),
+                ("age", &DataType::Int64),
+                ("score", &DataType::Float64),
+            ]
+        );
+        let rows: usize = reader.map(|batch| batch.unwrap().num_rows()).sum();
+        assert_eq!(rows, 2);
+    }
+
+    #[test]
+    fn test_empty_results_per_format() {
+        let results = grid(&[&["name", "age"]]);
+        assert_eq!(
+            rendered(|out| print_json(out, &results, "rows", None)),
+            "[]\n"
+        );
+        assert_eq!(
+            rendered(|out| print_csv(out, &results, false, b',')),
+            "name,age\n"
+        );
+    }
+
+    #[test]
+    fn test_checksum_is_deterministic() {
+        let csv = "name,age\nAnn,31\nBo,25\n";
+        let first = run_query("t >> sort age >> show name", csv.as_bytes()).unwrap();
+        let second = run_query("t >> sort age >> show name", csv.as_bytes()).unwrap();
+        assert_eq!(result_checksum(&first), result_checksum(&second));
+
+        let moved = grid(&[&["name"], &["BoAnn"], &[""]]);
+        assert_ne!(result_checksum(&first), result_checksum(&moved));
+    }
+
+    #[test]
+    fn test_index_column() {
+        let mut results = grid(&[&["name"], &["Ann"], &["Bo"], &["Cy"]]);
+        add_index_column(&mut results);
+        assert_eq!(
+            results,
+            grid(&[&["#", "name"], &["1", "Ann"], &["2", "Bo"], &["3", "Cy"]])
+        );
+    }
+
+    #[test]
+    fn test_table_and_stages_arguments() {
+        let args = cli(&["employees", "salary greater than 5 >> show name"]);
+        let input = query_input(&args).unwrap();
+        assert_eq!(input, "employees >> salary greater than 5 >> show name");
+        let query = prepare_query(&input, &args).unwrap();
+        assert_eq!(query.table_name, "employees.csv");
+        assert_eq!(query.columns, Some(vec!["name".to_string()]));
+
+        let args = cli(&["employees", ">> count"]);
+        assert_eq!(query_input(&args).unwrap(), "employees >> count");
+
+        let args = cli(&["employees >> count"]);
+        assert_eq!(query_input(&args).unwrap(), "employees >> count");
+    }
+
+    #[test]
+    fn test_delta_columns() {
+        let csv = "dept,name\nSales,Bo\nIT,Ann\nSales,Cy\nIT,Al\nSales,Sales\n";
+        let mut results = run_query("t >> sort dept >> show dept, name", csv.as_bytes()).unwrap();
+        blank_repeated_cells(&mut results);
+        assert_eq!(
+            results,
+            grid(&[
+                &["dept", "name"],
+                &["IT", "Ann"],
+                &["", "Al"],
+                &["Sales", "Bo"],
+                &["", "Cy"],
+                &["", "Sales"],
+            ])
+        );
+    }
+
+    #[test]
+    fn test_show_types() {
+        let results = grid(&[
+            &["name", "age", "joined"],
+            &["Ann", "31", "2024-01-05"],
+            &["Bo", "", "2023-11-30"],
+        ]);
+        let types = column_types(&results);
+        assert_eq!(types, vec!["text", "integer", "date"]);
+
+        let options = TableOptions {
+            width_sample: None,
+            highlight: None,
+            color_depth: "16",
+            skip_header: false,
+            interactive: false,
+            column_types: Some(types),
+        };
+        let table = rendered(|out| print_results(out, results, &options));
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].contains("name | age     | joined    "));
+        assert!(lines[1].contains("text | integer | date      "));
+    }
+
+    #[test]
+    fn test_kv_output() {
+        let results = grid(&[
+            &["name", "city", "note"],
+            &["Ann", "New York", ""],
+            &["Bo", "Oslo", r#"say "hi""#],
+        ]);
+        assert_eq!(
+            rendered(|out| print_kv(out, &results)),
+            "name=Ann city=\"New York\" note=\"\"\nname=Bo city=Oslo note=\"say \\\"hi\\\"\"\n"
+        );
+    }
+}